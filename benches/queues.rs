@@ -0,0 +1,132 @@
+// publishes to many queues at once while one of them syncs every append, first behind
+// one lock over every queue and then with the per-queue locks of `Queues`. Measures how
+// fast the other queues make progress. Run with `cargo bench --bench queues`
+use mq::internal::log::{CommitLog, Durability, LogConfig};
+use mq::Queues;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+const QUEUES: usize = 16;
+const MESSAGES: usize = 2000;
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+const DIR_PATH: &str = "bench_data/";
+const MESSAGE: &[u8] = &[7; 256];
+
+fn queue_name(n: usize) -> String {
+    format!("queue-{n}")
+}
+
+// queue 0 is the slow one, it syncs after every append
+fn durability(n: usize) -> Durability {
+    match n {
+        0 => Durability::EveryWrite,
+        _ => Durability::OsManaged,
+    }
+}
+
+// every append takes the write lock of the whole map, like the broker used to
+async fn global_lock() -> Duration {
+    let messages = Arc::new(RwLock::new(HashMap::new()));
+    for n in 0..QUEUES {
+        let mut log =
+            CommitLog::new(&queue_name(n), LogConfig::new(SEGMENT_SIZE), DIR_PATH).unwrap();
+        log.set_durability(durability(n));
+        messages.write().await.insert(queue_name(n), log);
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let slow = {
+        let (messages, done) = (messages.clone(), done.clone());
+        tokio::spawn(async move {
+            while !done.load(Ordering::Relaxed) {
+                let mut messages = messages.write().await;
+                let log: &mut CommitLog = messages.get_mut(&queue_name(0)).unwrap();
+                log.save_to_disk(MESSAGE).unwrap();
+            }
+        })
+    };
+    let start = Instant::now();
+    let tasks: Vec<_> = (1..QUEUES)
+        .map(|n| {
+            let messages = messages.clone();
+            tokio::spawn(async move {
+                for _ in 0..MESSAGES {
+                    let mut messages = messages.write().await;
+                    let log: &mut CommitLog = messages.get_mut(&queue_name(n)).unwrap();
+                    log.save_to_disk(MESSAGE).unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    done.store(true, Ordering::Relaxed);
+    slow.await.unwrap();
+    elapsed
+}
+
+// the map is only locked to look the queue up, the append holds the queue's own lock
+async fn per_queue_lock() -> Duration {
+    let queues = Arc::new(Queues::new(LogConfig::new(SEGMENT_SIZE), DIR_PATH));
+    for n in 0..QUEUES {
+        let queue = queues.get_or_create(&queue_name(n)).unwrap();
+        queue.lock().await.set_durability(durability(n));
+    }
+    let done = Arc::new(AtomicBool::new(false));
+    let slow = {
+        let (queues, done) = (queues.clone(), done.clone());
+        tokio::spawn(async move {
+            while !done.load(Ordering::Relaxed) {
+                let queue = queues.get(&queue_name(0)).unwrap();
+                queue.lock().await.save_to_disk(MESSAGE).unwrap();
+            }
+        })
+    };
+    let start = Instant::now();
+    let tasks: Vec<_> = (1..QUEUES)
+        .map(|n| {
+            let queues = queues.clone();
+            tokio::spawn(async move {
+                for _ in 0..MESSAGES {
+                    let queue = queues.get(&queue_name(n)).unwrap();
+                    queue.lock().await.save_to_disk(MESSAGE).unwrap();
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let elapsed = start.elapsed();
+    done.store(true, Ordering::Relaxed);
+    slow.await.unwrap();
+    elapsed
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let messages = ((QUEUES - 1) * MESSAGES) as f64;
+    println!(
+        "{name:<16} {:>8.0} msg/s over {} queues ({elapsed:?})",
+        messages / elapsed.as_secs_f64(),
+        QUEUES - 1
+    );
+}
+
+fn main() {
+    // appends block their worker while syncing, a worker per queue lets them overlap
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(QUEUES)
+        .enable_all()
+        .build()
+        .unwrap();
+    let _ = fs::remove_dir_all(DIR_PATH);
+    report("global lock", runtime.block_on(global_lock()));
+    let _ = fs::remove_dir_all(DIR_PATH);
+    report("per-queue lock", runtime.block_on(per_queue_lock()));
+    let _ = fs::remove_dir_all(DIR_PATH);
+}
@@ -1,91 +1,147 @@
-use mq::internal::log::CommitLog;
-use mq::{BinaryHeader, Result, Server};
-use std::collections::HashMap;
-use std::io::{self, ErrorKind};
+use futures::future::select_all;
+use mq::{BrokerConfig, Notifiers, Queues, Result, Server, USAGE};
+use std::io;
+use std::process::ExitCode;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{self, Duration};
 use tracing::{error, info};
 
-const PORT: u32 = 9000;
-const BUFFER: usize = 1024;
+// how often queues are checked for segments past their retention policy
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+// how often queues with compaction enabled are compacted
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(300);
+// how often queues in every_interval durability mode are checked for a due sync
+const SYNC_INTERVAL: Duration = Duration::from_millis(100);
+// how long connections get to finish their requests on shutdown
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[tokio::main]
-async fn main() -> Result<(), io::Error> {
+async fn main() -> ExitCode {
     tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::new())
         .expect("setting default subscriber failed");
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", PORT)).await?;
-    info!("INFO: listening on port:{}", PORT);
-    let messages = Arc::new(RwLock::new(HashMap::new()));
-    Server::restore_from_disk(messages.clone()).await;
-    loop {
-        match listener.accept().await {
-            Ok((stream, _addr)) => {
-                let messages_clone = Arc::clone(&messages);
-                tokio::spawn(async move {
-                    handle_incoming_connection(Arc::new(stream), messages_clone).await;
-                });
-            }
-            Err(e) => {
-                error!("Error accepting connection: {}", e);
-            }
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        println!("{USAGE}");
+        return ExitCode::SUCCESS;
+    }
+    let config = match BrokerConfig::load(args, |key| std::env::var(key).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    match run(config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("ERROR: {e}");
+            ExitCode::FAILURE
         }
     }
 }
 
-async fn handle_incoming_connection(
-    stream: Arc<TcpStream>,
-    messages: Arc<RwLock<HashMap<String, CommitLog>>>,
-) {
-    let mut leftover_data: Vec<u8> = Vec::new();
-    let mut server = Server::new(stream.clone(), messages.clone());
-
+async fn run(config: BrokerConfig) -> Result<(), io::Error> {
+    let mut listeners = Vec::new();
+    for addr in &config.listen {
+        listeners.push(TcpListener::bind(addr).await?);
+        info!("INFO: LISTENING ON {addr}");
+    }
+    let queues = Queues::new(config.log_config(), &config.data_dir)
+        .with_auto_create(config.auto_create_queues);
+    let queues = Arc::new(queues);
+    let notifiers = Arc::new(Notifiers::default());
+    Server::restore_from_disk(queues.clone())
+        .await
+        .map_err(|e| io::Error::other(format!("Failed to load queues: {e}")))?;
+    let retention_queues = Arc::clone(&queues);
+    let retention = tokio::spawn(async move {
+        let mut interval = time::interval(RETENTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            Server::apply_retention(retention_queues.clone()).await;
+        }
+    });
+    let compaction_queues = Arc::clone(&queues);
+    let compaction = tokio::spawn(async move {
+        let mut interval = time::interval(COMPACTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            Server::compact_queues(compaction_queues.clone()).await;
+        }
+    });
+    let sync_queues = Arc::clone(&queues);
+    let sync = tokio::spawn(async move {
+        let mut interval = time::interval(SYNC_INTERVAL);
+        loop {
+            interval.tick().await;
+            Server::sync_queues(sync_queues.clone()).await;
+        }
+    });
+    let (shutdown, shutdown_requested) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
     loop {
-        let mut buffer = vec![0u8; BUFFER];
-        let mut all_requests = Vec::new();
-
-        match stream.try_read(&mut buffer) {
-            Ok(bytes_read) => {
-                if bytes_read == 0 {
-                    break;
-                }
-                all_requests.extend_from_slice(&leftover_data);
-                all_requests.extend_from_slice(&buffer[..bytes_read]);
-                let mut offset = 0;
-                while offset < all_requests.len() {
-                    if all_requests.len() - offset < 8 {
-                        break;
-                    }
-                    // length of request
-                    let length =
-                        u32::from_be_bytes(all_requests[4..8].try_into().unwrap()) as usize;
-                    if offset + length > all_requests.len() {
-                        break;
-                    }
-                    let message_data = &all_requests[offset..offset + length];
-                    let tcp_header = BinaryHeader::from_bytes(message_data);
-                    server
-                        .decode_buffer(
-                            tcp_header.command,
-                            tcp_header.payload,
-                            tcp_header.queue_name,
-                        )
-                        .await;
-
-                    offset += tcp_header.length as usize;
-                }
-                leftover_data.clear();
-                leftover_data = all_requests[offset..].to_vec();
-            }
-            Err(err) => match err.kind() {
-                ErrorKind::WouldBlock => {
-                    continue;
+        tokio::select! {
+            _ = &mut signal => break,
+            accepted = select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))) => match accepted.0 {
+                Ok((stream, _addr)) => {
+                    let queues_clone = Arc::clone(&queues);
+                    let notifiers_clone = Arc::clone(&notifiers);
+                    let shutdown_clone = shutdown_requested.clone();
+                    connections.spawn(async move {
+                        Server::handle_connection(stream, queues_clone, notifiers_clone, shutdown_clone)
+                            .await;
+                    });
                 }
-                _ => {
-                    error!("ERROR: Got an unexpected error: {:?}", err);
-                    return;
+                Err(e) => {
+                    error!("Error accepting connection: {}", e);
                 }
             },
-        };
+            // forget connections that were closed
+            Some(_) = connections.join_next() => {}
+        }
+    }
+    info!("INFO: SHUTTING DOWN");
+    drop(listeners);
+    let _ = shutdown.send(true);
+    // connections finish the request they are handling before they are closed
+    let drained = time::timeout(DRAIN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        error!(
+            "ERROR: {} connections still busy, closing them",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
+    for task in [retention, compaction, sync] {
+        task.abort();
     }
+    Server::shutdown_queues(queues).await;
+    info!("INFO: SHUTDOWN COMPLETE");
+    Ok(())
+}
+
+// resolves on the first SIGINT or SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("installing SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+// resolves on the first ctrl-c, there is no SIGTERM to listen for
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -13,7 +13,7 @@ async fn main() -> Result<(), io::Error> {
         .expect("setting default subscriber failed");
 
     let mut id = 0;
-    let mut queue = MessageQueueClient::dial(ADDR).await?;
+    let queue = MessageQueueClient::dial(ADDR).await?;
     loop {
         queue
             .publish("new", format!("Hello World-{id}").as_bytes())
@@ -2,19 +2,27 @@
 use mq::MessageQueueClient;
 use mq::Result;
 use std::io;
-use tokio::time::sleep;
 use tokio::time::Duration;
+use tracing::info;
 
 static ADDR: &str = "127.0.0.1:9000";
+// messages the server may push before the subscriber processed them
+const CREDITS: u32 = 16;
 
 #[tokio::main]
 async fn main() -> Result<(), io::Error> {
     tracing::subscriber::set_global_default(tracing_subscriber::FmtSubscriber::new())
         .expect("setting default subscriber failed");
 
-    let mut queue = MessageQueueClient::dial(ADDR).await?;
-    loop {
-        queue.subscribe("new").await?;
-        sleep(Duration::from_millis(100)).await;
+    // optional consumer group, defaults to the queue's own group
+    let group = std::env::args().nth(1);
+    let queue = MessageQueueClient::dial(ADDR).await?;
+    queue
+        .stream("new", group.as_deref(), Duration::ZERO, CREDITS)
+        .await?;
+    while let Some(delivery) = queue.next_delivery().await? {
+        info!("{:?}", delivery);
+        queue.credit("new", 1).await?;
     }
+    Ok(())
 }
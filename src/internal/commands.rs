@@ -1,12 +1,29 @@
 use std::fmt::Display;
 #[derive(Debug)]
 #[repr(u32)]
+#[allow(non_camel_case_types)]
 pub enum Commands {
     QUIT = 0,
     SUBSCRIBE = 1,
     PUBLISH = 2,
     PING = 3,
     STATS = 4,
+    ACK = 5,
+    NACK = 6,
+    REPLAY = 7,
+    FETCH = 8,
+    SEEK = 9,
+    STREAM = 10,
+    CREDIT = 11,
+    PUBLISH_BATCH = 12,
+    HELLO = 13,
+    ALTER_QUEUE = 14,
+    CREATE_QUEUE = 15,
+    DELETE_QUEUE = 16,
+    LIST_QUEUES = 17,
+    DESCRIBE_QUEUE = 18,
+    PURGE_QUEUE = 19,
+    TRUNCATE_QUEUE = 20,
     UNKNOWN(String),
 }
 
@@ -31,6 +48,22 @@ impl Commands {
             2 => Commands::PUBLISH,
             3 => Commands::PING,
             4 => Commands::STATS,
+            5 => Commands::ACK,
+            6 => Commands::NACK,
+            7 => Commands::REPLAY,
+            8 => Commands::FETCH,
+            9 => Commands::SEEK,
+            10 => Commands::STREAM,
+            11 => Commands::CREDIT,
+            12 => Commands::PUBLISH_BATCH,
+            13 => Commands::HELLO,
+            14 => Commands::ALTER_QUEUE,
+            15 => Commands::CREATE_QUEUE,
+            16 => Commands::DELETE_QUEUE,
+            17 => Commands::LIST_QUEUES,
+            18 => Commands::DESCRIBE_QUEUE,
+            19 => Commands::PURGE_QUEUE,
+            20 => Commands::TRUNCATE_QUEUE,
             _ => Commands::UNKNOWN(format!("Unknown command: {}", value)),
         }
     }
@@ -0,0 +1,347 @@
+use crate::internal::log::{
+    Durability, LogConfig, RetentionPolicy, ENTRY_SIZE, INDEX_SIZE, SEGMENT_SIZE,
+};
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::fs;
+use std::time::Duration;
+
+pub const USAGE: &str =
+    "usage: mq [--config <file>] [--listen <addr>[,<addr>...]] [--data-dir <dir>]
+          [--segment-size <bytes>] [--index-size <bytes>]
+          [--retention-max-age-secs <secs>] [--retention-max-bytes <bytes>]
+          [--durability every_write|every_n_messages:<n>|every_interval:<ms>|os_managed]
+          [--auto-create-queues true|false]
+
+every flag can also be set through an MQ_ environment variable, e.g. MQ_DATA_DIR,
+flags take precedence over the environment which takes precedence over the file";
+
+// the settings that can be overridden by flags and MQ_ environment variables
+const KEYS: [&str; 8] = [
+    "listen",
+    "data-dir",
+    "segment-size",
+    "index-size",
+    "retention-max-age-secs",
+    "retention-max-bytes",
+    "durability",
+    "auto-create-queues",
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String),
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidValue { key: String, value: String },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "failed to read {path}: {e}"),
+            ConfigError::Parse(e) => write!(f, "invalid config file: {e}"),
+            ConfigError::UnknownOption(option) => write!(f, "unknown option {option}"),
+            ConfigError::MissingValue(option) => write!(f, "{option} needs a value"),
+            ConfigError::InvalidValue { key, value } => write!(f, "invalid {key}: {value:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// how long closed segments are kept, unset limits keep them forever
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub max_age_secs: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+// settings of the mq broker, read from a TOML file like
+//
+//   listen = ["127.0.0.1:9000"]
+//   data_dir = "storage/queue/"
+//   segment_size = 1048576
+//   index_size = 1048576
+//   durability = "every_interval:100"
+//
+//   [retention]
+//   max_age_secs = 604800
+//
+// retention and durability are the defaults of every queue
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    // addresses connections are accepted on
+    pub listen: Vec<String>,
+    // directory holding a subdirectory for every queue
+    pub data_dir: String,
+    pub segment_size: u64,
+    pub index_size: u64,
+    pub retention: RetentionConfig,
+    #[serde(deserialize_with = "deserialize_durability")]
+    pub durability: Durability,
+    // whether publishing to an unknown queue creates it
+    pub auto_create_queues: bool,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            listen: vec!["127.0.0.1:9000".to_string()],
+            data_dir: "storage/queue/".to_string(),
+            segment_size: SEGMENT_SIZE as u64,
+            index_size: INDEX_SIZE as u64,
+            retention: RetentionConfig::default(),
+            durability: Durability::default(),
+            auto_create_queues: true,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// the config file named by --config or MQ_CONFIG, or the defaults without one,
+    /// overridden by MQ_ environment variables and then by the command line flags
+    pub fn load<I>(args: I, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let flags = parse_flags(args)?;
+        let path = flags
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, path)| path.clone())
+            .or_else(|| env("MQ_CONFIG"));
+        let mut config = match path {
+            Some(path) => BrokerConfig::from_file(&path)?,
+            None => BrokerConfig::default(),
+        };
+        for key in KEYS {
+            if let Some(value) = env(&env_var(key)) {
+                config.set(key, &value)?;
+            }
+        }
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let data = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        BrokerConfig::from_toml(&data)
+    }
+    pub fn from_toml(data: &str) -> Result<Self, ConfigError> {
+        toml::from_str(data).map_err(|e| ConfigError::Parse(e.message().to_owned()))
+    }
+    /// override a setting by its flag name
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        let number = || value.parse::<u64>().map_err(|_| invalid());
+        match key {
+            "listen" => {
+                self.listen = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|addr| !addr.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }
+            "data-dir" => self.data_dir = value.to_owned(),
+            "segment-size" => self.segment_size = number()?,
+            "index-size" => self.index_size = number()?,
+            "retention-max-age-secs" => self.retention.max_age_secs = Some(number()?),
+            "retention-max-bytes" => self.retention.max_bytes = Some(number()?),
+            "durability" => self.durability = parse_durability(value).ok_or_else(invalid)?,
+            "auto-create-queues" => {
+                self.auto_create_queues = value.parse().map_err(|_| invalid())?
+            }
+            _ => return Err(ConfigError::UnknownOption(format!("--{key}"))),
+        }
+        Ok(())
+    }
+    /// the size and default policies of the broker's queues
+    pub fn log_config(&self) -> LogConfig {
+        LogConfig {
+            segment_size: self.segment_size,
+            index_size: self.index_size,
+            retention: RetentionPolicy {
+                max_age: self.retention.max_age_secs.map(Duration::from_secs),
+                max_bytes: self.retention.max_bytes,
+            },
+            durability: self.durability,
+            ..LogConfig::default()
+        }
+    }
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, value: String| ConfigError::InvalidValue {
+            key: key.to_owned(),
+            value,
+        };
+        if self.listen.is_empty() {
+            return Err(invalid("listen", String::new()));
+        }
+        if self.data_dir.is_empty() {
+            return Err(invalid("data-dir", String::new()));
+        }
+        if self.segment_size == 0 {
+            return Err(invalid("segment-size", self.segment_size.to_string()));
+        }
+        // a segment's index has to hold at least one record
+        if self.index_size < ENTRY_SIZE as u64 {
+            return Err(invalid("index-size", self.index_size.to_string()));
+        }
+        Ok(())
+    }
+}
+
+// splits `--key value` and `--key=value` flags into keys and values
+fn parse_flags<I>(args: I) -> Result<Vec<(String, String)>, ConfigError>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError::UnknownOption(arg));
+        };
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key.to_owned(), value.to_owned()),
+            None => {
+                let value = args.next().ok_or(ConfigError::MissingValue(arg.clone()))?;
+                (flag.to_owned(), value)
+            }
+        };
+        if key != "config" && !KEYS.contains(&key.as_str()) {
+            return Err(ConfigError::UnknownOption(arg));
+        }
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+// data-dir is read from MQ_DATA_DIR
+fn env_var(key: &str) -> String {
+    format!("MQ_{}", key.replace('-', "_").to_uppercase())
+}
+
+/// durability modes are written as every_write, os_managed, every_n_messages:<n> or
+/// every_interval:<milliseconds>
+pub fn parse_durability(value: &str) -> Option<Durability> {
+    let (mode, arg) = match value.split_once(':') {
+        Some((mode, arg)) => (mode, Some(arg.parse::<u64>().ok()?)),
+        None => (value, None),
+    };
+    match (mode, arg) {
+        ("every_write", None) => Some(Durability::EveryWrite),
+        ("os_managed", None) => Some(Durability::OsManaged),
+        ("every_n_messages", Some(n)) if n > 0 => {
+            Some(Durability::EveryNMessages(u32::try_from(n).ok()?))
+        }
+        ("every_interval", Some(ms)) => Some(Durability::EveryInterval(Duration::from_millis(ms))),
+        _ => None,
+    }
+}
+
+fn deserialize_durability<'de, D>(deserializer: D) -> Result<Durability, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_durability(&value)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid durability {value:?}")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_config_from_toml() {
+        let config = BrokerConfig::from_toml(
+            r#"
+            listen = ["0.0.0.0:9000", "[::1]:9001"]
+            data_dir = "/var/lib/mq"
+            segment_size = 4096
+            durability = "every_n_messages:10"
+            auto_create_queues = false
+
+            [retention]
+            max_age_secs = 60
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen, vec!["0.0.0.0:9000", "[::1]:9001"]);
+        assert_eq!(config.data_dir, "/var/lib/mq");
+        assert_eq!(config.index_size, INDEX_SIZE as u64);
+        assert!(!config.auto_create_queues);
+        let log = config.log_config();
+        assert_eq!(log.segment_size, 4096);
+        assert_eq!(log.durability, Durability::EveryNMessages(10));
+        assert_eq!(log.retention.max_age, Some(Duration::from_secs(60)));
+        assert_eq!(log.retention.max_bytes, None);
+        // typos are reported instead of silently using the defaults
+        assert!(BrokerConfig::from_toml("segment_sise = 1").is_err());
+        assert!(BrokerConfig::from_toml("durability = \"sometimes\"").is_err());
+    }
+
+    #[test]
+    fn test_config_overrides() {
+        let env = HashMap::from([
+            ("MQ_DATA_DIR", "/from/env"),
+            ("MQ_SEGMENT_SIZE", "100"),
+            ("MQ_DURABILITY", "every_write"),
+            ("MQ_AUTO_CREATE_QUEUES", "false"),
+        ]);
+        let config = BrokerConfig::load(
+            args(&["--segment-size", "200", "--listen=127.0.0.1:1,127.0.0.1:2"]),
+            |key| env.get(key).map(|value| value.to_string()),
+        )
+        .unwrap();
+        // flags win over the environment which wins over the defaults
+        assert_eq!(config.segment_size, 200);
+        assert_eq!(config.data_dir, "/from/env");
+        assert_eq!(config.durability, Durability::EveryWrite);
+        assert_eq!(config.listen, vec!["127.0.0.1:1", "127.0.0.1:2"]);
+        assert_eq!(config.index_size, INDEX_SIZE as u64);
+        assert!(!config.auto_create_queues);
+
+        let load = |flags: &[&str]| BrokerConfig::load(args(flags), |_| None);
+        assert!(matches!(
+            load(&["--port", "1"]),
+            Err(ConfigError::UnknownOption(_))
+        ));
+        assert!(matches!(
+            load(&["--data-dir"]),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            load(&["--index-size", "8"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(&["--durability", "every_n_messages:0"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(&["--auto-create-queues", "no"]),
+            Err(ConfigError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            load(&["--config", "/no/such/mq.toml"]),
+            Err(ConfigError::Io(..))
+        ));
+    }
+}
@@ -1,22 +1,24 @@
-// TODO: crc implementation data consistency
-// TODO: Log Replication and Log Compaction
+// TODO: Log Replication
 #![allow(dead_code)]
 #![allow(
     clippy::needless_question_mark,
     clippy::seek_from_current,
     clippy::unused_io_amount
 )]
+use crate::internal::checksum::crc32c;
 use crate::Result;
 use core::str;
 use memmap2::{MmapMut, MmapOptions, RemapOptions};
 use std::{
     borrow::BorrowMut,
+    collections::{hash_map, BTreeMap, HashMap},
     fmt::{Debug, Display},
     fs::{self, File, OpenOptions},
     io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    os::unix::fs::FileExt,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
 };
+use tracing::warn;
 
 #[derive(Debug)]
 pub enum StorageError {
@@ -26,6 +28,24 @@ pub enum StorageError {
     DirEmpty,
     InvalidSeek,
     LogIndexOutofBound,
+    // names used for files on disk can not be empty or contain path separators
+    InvalidName,
+    // the acknowledged message was not handed out to the consumer group
+    NotInFlight,
+    // the record read back from a segment does not match its checksum
+    CorruptRecord { segment: u32, offset: u32 },
+    // the message is larger than the queue's max_message_size
+    MessageTooLarge,
+    // the queue settings are out of range, like a segment size of 0
+    InvalidConfig,
+    // a queue with the name already exists
+    QueueExists,
+    // the queue was deleted, nothing can be appended to it anymore
+    Deleted,
+    // the files of a queue are in a layout this version can't read
+    UnsupportedFormat(u8),
+    // the meta file of a queue can't be decoded or was written by a newer version
+    InvalidSettings,
 }
 
 impl Display for StorageError {
@@ -33,23 +53,61 @@ impl Display for StorageError {
         write!(f, "{}", format!("{:?}", self).to_lowercase())
     }
 }
-pub const SEGMENT_SIZE: usize = 1024 * 1024; //TODO: should be a setting
+// defaults of a LogConfig
+pub const SEGMENT_SIZE: usize = 1024 * 1024;
+pub const INDEX_SIZE: usize = 1024 * 1024;
 const START_OFFSET: usize = 0;
 // represents the size of our entry/idx byte size
-const ENTRY_SIZE: usize = 8;
+pub const ENTRY_SIZE: usize = 28;
 const DIR_PATH: &str = "storage/queue/";
+// left in a queue's directory by `shutdown`, its files are consistent while it exists
+const CLEAN_SHUTDOWN: &str = "clean_shutdown";
+// the QueueSettings of a queue, kept in its directory
+const SETTINGS_FILE: &str = "meta";
+const SETTINGS_VERSION: u8 = 1;
+// the layout version of a queue's index and tracker files, kept in its directory.
+// 1 had 8 byte index entries of [offset][size], 2 added the checksum, message
+// offset and timestamp to make them 28 bytes
+const FORMAT_FILE: &str = "format";
+const FORMAT_VERSION: u8 = 2;
+const V1_ENTRY_SIZE: usize = 8;
+// files rewritten by a migration wait under this extension until the new version
+// is recorded, then replace the originals
+const MIGRATE_EXTENSION: &str = "migrate";
+// the files of a segment being rewritten, renaming the log over the original commits
+// the rewrite
+const COMPACT_EXTENSION: &str = "compact";
+// the message offset the log starts at after `truncate_before`, an empty first
+// segment has no record to read it from
+const LOG_START_FILE: &str = "log_start";
+// bytes of an encoded LogConfig
+pub const LOG_CONFIG_SIZE: usize = 62;
 
 pub struct CommitLog {
     pub name: String,
     pub segments: Vec<Segment>,
     pub dir_path: PathBuf,
     segment_size: u64,
-    // track where the last read from the client to queue was from
-    pub queue_pos: File,
-    // keep track of the last segment to be read
-    rposition: u32,
-    // keep track of the offset that was  last read by client
-    rindex_offset: u32,
+    index_size: u64,
+    retention: RetentionPolicy,
+    compaction: Option<CompactionPolicy>,
+    // messages handed out this many times without an ACK are moved to the dead letters
+    max_deliveries: Option<u32>,
+    // messages that exceeded max_deliveries waiting to be moved to the dead letter queue
+    dead_letters: Vec<DeadLetter>,
+    // the read cursor of every consumer group, the group named after the queue is
+    // the default one and its tracker also holds the last write offset
+    groups: HashMap<String, ConsumerGroup>,
+    durability: Durability,
+    // larger messages are refused
+    max_message_size: Option<u32>,
+    // milliseconds since the unix epoch
+    created_at: u64,
+    // messages appended since the last sync
+    unsynced: u32,
+    last_sync: Instant,
+    // the directory was removed by `delete`
+    deleted: bool,
     // keep track of the last segment to be written
     wposition: u32,
     // keep track of the offset that was  last write by client
@@ -59,13 +117,22 @@ pub struct CommitLog {
 pub struct Segment {
     pub log: Log,
     path: PathBuf,
+    // message offset of the first record in the segment
     base_offset: u64,
+    // message offset the next record appended to the segment gets
+    next_offset: u64,
+    // append time of the newest record, new records never get an older timestamp
+    max_timestamp: u64,
     pub current_offset: u64,
     segment_size: u64,
     closed: bool,
+    // appended to since the last sync
+    dirty: bool,
 }
 
 pub struct Log {
+    // position of the segment this log belongs to
+    id: u32,
     writer: CursorWriter<File>,
     reader: CursorReader<File>,
     pub index: Index,
@@ -80,6 +147,244 @@ pub struct Index {
     max_size: usize,
 }
 
+// the settings of a queue. Sizes only apply to segments created after they changed.
+// Encoded as [segment size u64][index size u64][max age][max bytes][durability]
+// [max message size][compaction][max deliveries], optional values start with a
+// presence byte and durations are in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogConfig {
+    // bytes of record data a segment holds before a new one is started
+    pub segment_size: u64,
+    // bytes reserved for the index of a segment, every record takes ENTRY_SIZE of them
+    pub index_size: u64,
+    pub retention: RetentionPolicy,
+    pub durability: Durability,
+    pub max_message_size: Option<u32>,
+    pub compaction: Option<CompactionPolicy>,
+    // messages handed out this many times without an ACK are moved to the dead letters
+    pub max_deliveries: Option<u32>,
+}
+
+impl LogConfig {
+    pub fn new(segment_size: u64) -> LogConfig {
+        LogConfig {
+            segment_size,
+            ..LogConfig::default()
+        }
+    }
+    pub fn validate(&self) -> Result<(), StorageError> {
+        let valid = self.segment_size > 0
+            && self.index_size >= ENTRY_SIZE as u64
+            && self.durability != Durability::EveryNMessages(0)
+            && self.max_message_size != Some(0);
+        match valid {
+            true => Ok(()),
+            false => Err(StorageError::InvalidConfig),
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::with_capacity(LOG_CONFIG_SIZE);
+        payload.extend(self.segment_size.to_be_bytes());
+        payload.extend(self.index_size.to_be_bytes());
+        let max_age = self.retention.max_age.map(|age| age.as_millis() as u64);
+        encode_option_u64(&mut payload, max_age);
+        encode_option_u64(&mut payload, self.retention.max_bytes);
+        let (mode, arg) = match self.durability {
+            Durability::OsManaged => (0, 0),
+            Durability::EveryWrite => (1, 0),
+            Durability::EveryNMessages(n) => (2, n as u64),
+            Durability::EveryInterval(interval) => (3, interval.as_millis() as u64),
+        };
+        payload.push(mode);
+        payload.extend(arg.to_be_bytes());
+        encode_option_u32(&mut payload, self.max_message_size);
+        let grace = self
+            .compaction
+            .map(|policy| policy.tombstone_grace.as_millis() as u64);
+        encode_option_u64(&mut payload, grace);
+        encode_option_u32(&mut payload, self.max_deliveries);
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<LogConfig> {
+        if data.len() < LOG_CONFIG_SIZE {
+            return None;
+        }
+        let u64_at = |at: usize| u64::from_be_bytes(data[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+        let option_u64 = |at: usize| (data[at] == 1).then(|| u64_at(at + 1));
+        let option_u32 = |at: usize| (data[at] == 1).then(|| u32_at(at + 1));
+        let durability = match (data[34], u64_at(35)) {
+            (0, _) => Durability::OsManaged,
+            (1, _) => Durability::EveryWrite,
+            (2, n) => Durability::EveryNMessages(u32::try_from(n).ok()?),
+            (3, ms) => Durability::EveryInterval(Duration::from_millis(ms)),
+            _ => return None,
+        };
+        Some(LogConfig {
+            segment_size: u64_at(0),
+            index_size: u64_at(8),
+            retention: RetentionPolicy {
+                max_age: option_u64(16).map(Duration::from_millis),
+                max_bytes: option_u64(25),
+            },
+            durability,
+            max_message_size: option_u32(43),
+            compaction: option_u64(48).map(|ms| CompactionPolicy {
+                tombstone_grace: Duration::from_millis(ms),
+            }),
+            max_deliveries: option_u32(57),
+        })
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            segment_size: SEGMENT_SIZE as u64,
+            index_size: INDEX_SIZE as u64,
+            retention: RetentionPolicy::default(),
+            durability: Durability::default(),
+            max_message_size: None,
+            compaction: None,
+            max_deliveries: None,
+        }
+    }
+}
+
+fn encode_option_u64(payload: &mut Vec<u8>, value: Option<u64>) {
+    payload.push(value.is_some() as u8);
+    payload.extend(value.unwrap_or_default().to_be_bytes());
+}
+
+fn encode_option_u32(payload: &mut Vec<u8>, value: Option<u32>) {
+    payload.push(value.is_some() as u8);
+    payload.extend(value.unwrap_or_default().to_be_bytes());
+}
+
+// the contents of a queue's meta file, [version u8][created at u64][LogConfig]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueSettings {
+    // milliseconds since the unix epoch
+    pub created_at: u64,
+    pub config: LogConfig,
+}
+
+impl QueueSettings {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::with_capacity(9 + LOG_CONFIG_SIZE);
+        payload.push(SETTINGS_VERSION);
+        payload.extend(self.created_at.to_be_bytes());
+        payload.extend(self.config.to_bytes());
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<QueueSettings> {
+        if data.len() < 9 || data[0] != SETTINGS_VERSION {
+            return None;
+        }
+        Some(QueueSettings {
+            created_at: u64::from_be_bytes(data[1..9].try_into().unwrap()),
+            config: LogConfig::from_bytes(&data[9..])?,
+        })
+    }
+}
+
+// decides when closed segments of a queue are deleted, unset limits keep data forever
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RetentionPolicy {
+    // delete closed segments whose newest record is older than this
+    pub max_age: Option<Duration>,
+    // delete the oldest closed segments while the queue holds more bytes than this
+    pub max_bytes: Option<u64>,
+}
+
+// when appends are fsynced to disk, data written since the last sync can be lost on a
+// crash of the machine. Applies to the log, its index and the consumer group trackers
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Durability {
+    // sync after every append, appends return once the message is durable
+    EveryWrite,
+    // sync once every n appended messages
+    EveryNMessages(u32),
+    // sync on the first append after the interval passed and when `sync_if_due` is called
+    EveryInterval(Duration),
+    // leave writing dirty pages back to the OS
+    #[default]
+    OsManaged,
+}
+
+// enables key based compaction of a queue's closed segments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionPolicy {
+    // how long a tombstone is kept so consumers get to see the delete
+    pub tombstone_grace: Duration,
+}
+
+// the compaction key of a stored record
+#[derive(Debug, PartialEq)]
+pub struct RecordKey {
+    pub key: Vec<u8>,
+    // an empty value that deletes the key once compacted
+    pub tombstone: bool,
+}
+
+// a named reader of the queue, each group gets every message and keeps its own
+// cursor in the offsets/<group> file
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    // track where the last read from the client to queue was from
+    path: PathBuf,
+    // keep track of the last segment to be read
+    position: u32,
+    // keep track of the offset that was  last read by client
+    offset: u32,
+    // messages handed out but not acknowledged yet, persisted after the cursor in the
+    // group's tracker so delivery attempts survive a restart
+    in_flight: BTreeMap<RecordId, InFlight>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct InFlight {
+    // when the message is handed out again unless acknowledged
+    visible_at: Instant,
+    // how many times the message was handed out
+    deliveries: u32,
+}
+
+// a message that was handed out max_deliveries times without being acknowledged
+#[derive(Debug, PartialEq)]
+pub struct DeadLetter {
+    pub group: String,
+    pub id: RecordId,
+    pub deliveries: u32,
+    pub message: Vec<u8>,
+}
+
+// the address of a record handed out to a consumer, made of the id of the segment
+// and the index offset of the record so it stays valid when older segments are deleted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RecordId {
+    pub segment: u32,
+    pub offset: u32,
+}
+
+impl RecordId {
+    pub fn to_u64(&self) -> u64 {
+        ((self.segment as u64) << 32) | self.offset as u64
+    }
+    pub fn from_u64(id: u64) -> Self {
+        RecordId {
+            segment: (id >> 32) as u32,
+            offset: id as u32,
+        }
+    }
+}
+
+/// whether the name can be used for a queue or consumer group, it names a file or
+/// directory on disk
+pub fn valid_name(name: &str) -> bool {
+    !(name.is_empty() || name.contains('/') || name == "." || name == "..")
+}
+
 //will be used to track the position of commit log and save to disk
 #[derive(Debug)]
 pub struct Tracker {
@@ -90,6 +395,96 @@ pub struct Tracker {
     last_write_offset: u32,
 }
 
+impl ConsumerGroup {
+    // open the tracker of a group, groups that never read start at the beginning of the log
+    fn open(offsets_path: &Path, name: &str) -> Result<(Self, Tracker), StorageError> {
+        if !valid_name(name) {
+            return Err(StorageError::InvalidName);
+        }
+        let path = offsets_path.join(name);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        if buf.len() < 12 {
+            buf.resize(12, 0);
+        }
+        let tracker = Tracker::from_bytes(&buf);
+        // messages that were in flight when the tracker was saved are visible right away
+        let now = Instant::now();
+        let mut in_flight = BTreeMap::new();
+        if buf.len() >= 16 {
+            let count = u32::from_be_bytes(buf[12..16].try_into().unwrap()) as usize;
+            for entry in buf[16..].chunks_exact(12).take(count) {
+                let id = RecordId::from_u64(u64::from_be_bytes(entry[..8].try_into().unwrap()));
+                let deliveries = u32::from_be_bytes(entry[8..12].try_into().unwrap());
+                in_flight.insert(
+                    id,
+                    InFlight {
+                        visible_at: now,
+                        deliveries,
+                    },
+                );
+            }
+        }
+        let group = ConsumerGroup {
+            path,
+            position: tracker.position,
+            offset: tracker.offset,
+            in_flight,
+        };
+        Ok((group, tracker))
+    }
+    // persists the cursor followed by the delivery attempts of the messages in flight.
+    // The tracker is replaced in one rename so a crash leaves the old or the new one,
+    // `sync` makes it durable
+    fn save(&self, last_write_offset: u32) -> Result<(), StorageError> {
+        let mut payload = Tracker::to_bytes(self.position, self.offset, last_write_offset);
+        payload.extend((self.in_flight.len() as u32).to_be_bytes());
+        for (id, in_flight) in &self.in_flight {
+            payload.extend(id.to_u64().to_be_bytes());
+            payload.extend(in_flight.deliveries.to_be_bytes());
+        }
+        // written next to the offsets directory, every file in it is a group
+        let offsets_path = self.path.parent().expect("offsets directory");
+        let name = self.path.file_name().expect("group name").to_string_lossy();
+        let tmp_path = offsets_path.with_file_name(format!("{name}.tracker.tmp"));
+        fs::write(&tmp_path, payload)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+    fn sync(&self) -> Result<(), StorageError> {
+        File::open(&self.path)?.sync_all()?;
+        File::open(self.path.parent().expect("offsets directory"))?.sync_all()?;
+        Ok(())
+    }
+    // move the cursor to the next record
+    fn advance(&mut self, segments: &[Segment]) -> Result<RecordId, StorageError> {
+        let len = segments.len() - 1;
+        loop {
+            let segment_offset = segments[self.position as usize].log.index.offset as u32;
+            if self.offset < segment_offset {
+                break;
+            }
+            if self.position == len as u32 {
+                self.offset = segment_offset;
+                return Err(StorageError::LogIndexOutofBound);
+            }
+            self.position += 1;
+            self.offset = 0;
+        }
+        self.offset += ENTRY_SIZE as u32;
+        Ok(RecordId {
+            segment: segments[self.position as usize].id(),
+            offset: self.offset,
+        })
+    }
+}
+
 impl Tracker {
     pub fn to_bytes(position: u32, offset: u32, lwoffset: u32) -> Vec<u8> {
         let mut payload: Vec<u8> = Vec::with_capacity(
@@ -115,11 +510,18 @@ impl Tracker {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Entry {
     offset: u32,
     size: u32,
+    // crc32c checksum of the record data
+    crc: u32,
+    // global offset of the message, increasing across all segments of the queue
+    message_offset: u64,
+    // milliseconds since the unix epoch the message was appended at, never decreasing
+    timestamp: u64,
 }
+
 pub struct CursorWriter<T: Write + Seek> {
     writer: BufWriter<T>,
     position: u64,
@@ -216,74 +618,569 @@ impl Iterator for CommitLog {
 }
 
 impl CommitLog {
-    pub fn new(queue_name: &str, segment_size: u64, dir_path: &str) -> Self {
+    pub fn new(queue_name: &str, config: LogConfig, dir_path: &str) -> Result<Self, StorageError> {
         let dir_path = format!("{}{}", dir_path, queue_name);
         let mut segments: Vec<Segment> = Vec::new();
         let offsets_path = format!("{}/{}", dir_path, "offsets");
-        fs::create_dir_all(&offsets_path).unwrap();
-        let file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(format!("{offsets_path}/{}", queue_name))
-            .unwrap();
-        let segment = Segment::new(&dir_path, 0, segment_size);
+        fs::create_dir_all(&offsets_path)?;
+        let (group, _) = ConsumerGroup::open(Path::new(&offsets_path), queue_name)?;
+        let segment = Segment::new(&dir_path, 0, config.segment_size, config.index_size)?;
         segments.push(segment);
-        CommitLog {
+        let log = CommitLog {
             name: queue_name.to_owned(),
             segments,
-            segment_size,
+            segment_size: config.segment_size,
+            index_size: config.index_size,
+            retention: config.retention,
+            compaction: config.compaction,
+            max_deliveries: config.max_deliveries,
+            dead_letters: Vec::new(),
             dir_path: PathBuf::from(dir_path),
-            queue_pos: file,
-            rposition: 0,
-            rindex_offset: 0,
+            groups: HashMap::from([(queue_name.to_owned(), group)]),
+            durability: config.durability,
+            max_message_size: config.max_message_size,
+            created_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|now| now.as_millis() as u64)
+                .unwrap_or(0),
+            unsynced: 0,
+            last_sync: Instant::now(),
+            deleted: false,
             windex_offset: 0,
             wposition: 0,
+        };
+        log.save_settings()?;
+        write_format(&log.dir_path)?;
+        Ok(log)
+    }
+    /// the settings the queue runs with
+    pub fn config(&self) -> LogConfig {
+        LogConfig {
+            segment_size: self.segment_size,
+            index_size: self.index_size,
+            retention: self.retention,
+            durability: self.durability,
+            max_message_size: self.max_message_size,
+            compaction: self.compaction,
+            max_deliveries: self.max_deliveries,
+        }
+    }
+    pub fn settings(&self) -> QueueSettings {
+        QueueSettings {
+            created_at: self.created_at,
+            config: self.config(),
         }
     }
-    /// Save data to disk by appending to the current segment
-    pub fn save_to_disk(&mut self, data: &[u8]) -> Result<(), StorageError> {
-        self.save_data_to_segment(data)
+    /// change every setting of the queue at once and persist them, sizes apply to
+    /// the segments created from now on
+    pub fn set_config(&mut self, config: LogConfig) -> Result<(), StorageError> {
+        config.validate()?;
+        self.segment_size = config.segment_size;
+        self.index_size = config.index_size;
+        self.retention = config.retention;
+        self.durability = config.durability;
+        self.max_message_size = config.max_message_size;
+        self.compaction = config.compaction;
+        self.max_deliveries = config.max_deliveries;
+        self.save_settings()
+    }
+    // write the settings next to the segments, replacing the old file in one rename
+    fn save_settings(&self) -> Result<(), StorageError> {
+        let tmp_path = self.dir_path.join(format!("{SETTINGS_FILE}.tmp"));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&self.settings().to_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.dir_path.join(SETTINGS_FILE))?;
+        Ok(())
+    }
+    // written like the settings so a crash leaves either the old or the new start
+    fn save_log_start(&self, start: u64) -> Result<(), StorageError> {
+        let tmp_path = self.dir_path.join(format!("{LOG_START_FILE}.tmp"));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&start.to_be_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, self.dir_path.join(LOG_START_FILE))?;
+        Ok(())
+    }
+    // setters persist the settings but can't fail, the change still applies until restart
+    fn settings_changed(&self) {
+        if let Err(e) = self.save_settings() {
+            warn!(
+                "WARN: Failed to save the settings of queue {}: {e}",
+                self.name
+            );
+        }
+    }
+    /// Save data to disk by appending to the current segment, returns the message offset
+    pub fn save_to_disk(&mut self, data: &[u8]) -> Result<u64, StorageError> {
+        self.save_data_to_segment(&[data])
+    }
+    /// Save records to disk as one append, they all end up in the same segment and are
+    /// flushed and synced once. Returns the message offset of every record
+    pub fn save_batch(&mut self, records: &[&[u8]]) -> Result<Vec<u64>, StorageError> {
+        if records.is_empty() {
+            return Ok(Vec::new());
+        }
+        let first_offset = self.save_data_to_segment(records)?;
+        Ok((first_offset..first_offset + records.len() as u64).collect())
     }
     /// Append data to the current segment, or create a new segment if necessary
-    fn save_data_to_segment(&mut self, data: &[u8]) -> Result<(), StorageError> {
+    fn save_data_to_segment(&mut self, records: &[&[u8]]) -> Result<u64, StorageError> {
+        if self.deleted {
+            return Err(StorageError::Deleted);
+        }
+        // records that wouldn't fit an empty segment either must not close the current one
+        if let Some(max) = self.max_message_size {
+            if records.iter().any(|data| data.len() > max as usize) {
+                return Err(StorageError::MessageTooLarge);
+            }
+        }
+        let size: u64 = records.iter().map(|data| data.len() as u64).sum();
+        if size > self.segment_size || (records.len() * ENTRY_SIZE) as u64 > self.index_size {
+            return Err(StorageError::NoSpaceLeft);
+        }
         let len_segments = self.segments.len();
 
-        self.windex_offset = self.segments[self.wposition as usize].log.index.offset as u32;
         let segment = &mut self.segments[len_segments - 1];
-        match segment.append_data(data) {
-            Ok(_) => {
-                self.save_queue_offset();
-                Ok(())
+        match segment.append_batch(records) {
+            Ok(message_offset) => {
+                self.windex_offset = segment.log.index.offset as u32;
+                self.save_queue_offset()?;
+                self.written(records.len() as u32)?;
+                Ok(message_offset)
             }
             Err(e) => match e {
                 StorageError::NoSpaceLeft => {
-                    segment.log.index.resize();
-                    segment.closed = true;
                     // Handle segment full scenario by closing the current segment and creating a new one
-                    let mut new_segment = self.create_new_segment(len_segments as u32);
-                    new_segment.append_data(data)?;
+                    self.roll_segment()?;
+                    let new_segment = self.segments.last_mut().expect("active segment");
+                    let message_offset = new_segment.append_batch(records)?;
                     self.windex_offset = new_segment.log.index.offset as u32;
-                    self.segments.push(new_segment);
-                    self.wposition += 1;
-                    self.save_queue_offset();
-                    Ok(())
+                    self.save_queue_offset()?;
+                    self.written(records.len() as u32)?;
+                    Ok(message_offset)
                 }
                 _ => Err(e),
             },
         }
     }
+    // count appended messages and sync when the durability mode asks for it
+    fn written(&mut self, messages: u32) -> Result<(), StorageError> {
+        self.unsynced += messages;
+        let due = match self.durability {
+            Durability::EveryWrite => true,
+            Durability::EveryNMessages(n) => self.unsynced >= n,
+            Durability::EveryInterval(interval) => self.last_sync.elapsed() >= interval,
+            Durability::OsManaged => false,
+        };
+        match due {
+            true => self.sync(),
+            false => Ok(()),
+        }
+    }
+    /// fsync every segment written since the last sync and the consumer group trackers
+    pub fn sync(&mut self) -> Result<(), StorageError> {
+        for segment in self.segments.iter_mut().filter(|segment| segment.dirty) {
+            segment.sync()?;
+        }
+        for group in self.groups.values() {
+            group.sync()?;
+        }
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+    /// flush and fsync everything then mark the queue as cleanly shut down, the next
+    /// `restore_from_disk` trusts its files instead of recovering them. Nothing may be
+    /// appended afterwards
+    pub fn shutdown(&mut self) -> Result<(), StorageError> {
+        if self.deleted {
+            return Ok(());
+        }
+        self.save_group_offsets()?;
+        for segment in self.segments.iter_mut() {
+            segment.sync()?;
+        }
+        for group in self.groups.values() {
+            group.sync()?;
+        }
+        File::create(self.dir_path.join(CLEAN_SHUTDOWN))?.sync_all()?;
+        File::open(&self.dir_path)?.sync_all()?;
+        Ok(())
+    }
+    /// remove the queue's directory with its segments, settings and consumer offsets.
+    /// Appends fail with `Deleted` afterwards, reads only see what was already mapped
+    pub fn delete(&mut self) -> Result<(), StorageError> {
+        self.deleted = true;
+        fs::remove_dir_all(&self.dir_path)?;
+        Ok(())
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+    /// sync the queue when it runs in `EveryInterval` mode and the interval passed
+    /// with messages left unsynced
+    pub fn sync_if_due(&mut self) -> Result<(), StorageError> {
+        match self.durability {
+            Durability::EveryInterval(interval)
+                if self.unsynced > 0 && self.last_sync.elapsed() >= interval =>
+            {
+                self.sync()
+            }
+            _ => Ok(()),
+        }
+    }
+    /// whether every appended message was synced to disk
+    pub fn is_synced(&self) -> bool {
+        self.unsynced == 0
+    }
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+        self.settings_changed();
+    }
+    // sync a consumer group's tracker right away when every write has to be durable
+    fn sync_tracker(&self, group: &str) -> Result<(), StorageError> {
+        match (self.durability, self.groups.get(group)) {
+            (Durability::EveryWrite, Some(consumer)) => consumer.sync(),
+            _ => Ok(()),
+        }
+    }
+    // close the segment being written to and append to a new one from now on
+    fn roll_segment(&mut self) -> Result<(), StorageError> {
+        // the new segment is created first so a failure leaves the active one open
+        let next_id = self.segments.last().expect("active segment").id() + 1;
+        let new_segment = self.create_new_segment(next_id)?;
+        let segment = self.segments.last_mut().expect("active segment");
+        segment.log.index.resize();
+        segment.closed = true;
+        self.segments.push(new_segment);
+        self.wposition += 1;
+        self.windex_offset = 0;
+        Ok(())
+    }
     // helper method to create  new segments the segments are created in ascening order from 0
-    fn create_new_segment(&mut self, pos: u32) -> Segment {
-        Segment::new(
+    fn create_new_segment(&mut self, pos: u32) -> Result<Segment, StorageError> {
+        let mut segment = Segment::new(
             self.dir_path.as_os_str().to_str().expect("queue path"),
             pos,
             self.segment_size,
-        )
+            self.index_size,
+        )?;
+        let last = &self.segments[self.segments.len() - 1];
+        segment.base_offset = last.next_offset;
+        segment.next_offset = last.next_offset;
+        segment.max_timestamp = last.max_timestamp;
+        Ok(segment)
+    }
+    /// the offset the next message appended to the queue gets
+    pub fn next_offset(&self) -> u64 {
+        self.segments[self.segments.len() - 1].next_offset
+    }
+    /// the offset of the oldest message still stored
+    pub fn start_offset(&self) -> u64 {
+        self.segments
+            .iter()
+            .find(|segment| segment.entries() > 0)
+            .map(|segment| segment.base_offset)
+            .unwrap_or_else(|| self.next_offset())
+    }
+    /// the offset of the first message appended at or after `timestamp`, given in
+    /// milliseconds since the unix epoch. The next offset when there is none
+    pub fn offset_for_time(&self, timestamp: u64) -> u64 {
+        self.segments
+            .iter()
+            .find_map(|segment| segment.offset_for_time(timestamp))
+            .unwrap_or_else(|| self.next_offset())
+    }
+    /// reads up to `max_messages` messages starting at the given offset without moving
+    /// any consumer group, the first message is returned even when it is larger than
+    /// `max_bytes`. Offsets deleted by retention start at the oldest message left
+    pub fn fetch(
+        &mut self,
+        offset: u64,
+        max_messages: u32,
+        max_bytes: u32,
+    ) -> Result<Vec<(u64, Vec<u8>)>, StorageError> {
+        let mut messages = Vec::new();
+        let mut bytes = 0;
+        for segment in self.segments.iter_mut() {
+            if segment.next_offset <= offset {
+                continue;
+            }
+            let Some(start) = segment.position_of(offset) else {
+                continue;
+            };
+            for position in (start..=segment.log.index.offset).step_by(ENTRY_SIZE) {
+                let entry = segment.entry(position / ENTRY_SIZE - 1);
+                if messages.len() >= max_messages as usize
+                    || (!messages.is_empty() && bytes + entry.size as usize > max_bytes as usize)
+                {
+                    return Ok(messages);
+                }
+                let data = segment.read_at(position)?;
+                bytes += data.len();
+                messages.push((entry.message_offset, data));
+            }
+        }
+        Ok(messages)
+    }
+
+    pub fn retention(&self) -> RetentionPolicy {
+        self.retention
+    }
+    pub fn set_retention(&mut self, policy: RetentionPolicy) {
+        self.retention = policy;
+        self.settings_changed();
+    }
+    /// Delete the closed segments that fall outside the retention policy, the segment
+    /// being written to is never removed. Returns the number of segments deleted
+    pub fn apply_retention(&mut self) -> Result<usize, StorageError> {
+        if self.deleted {
+            return Ok(0);
+        }
+        let closed = self.segments.len() - 1;
+        let mut expired = 0;
+        if let Some(max_age) = self.retention.max_age {
+            let now = SystemTime::now();
+            while expired < closed {
+                match now.duration_since(self.segments[expired].newest_record()) {
+                    Ok(age) if age >= max_age => expired += 1,
+                    _ => break,
+                }
+            }
+        }
+        if let Some(max_bytes) = self.retention.max_bytes {
+            let mut total: u64 = self.segments[expired..].iter().map(|s| s.size()).sum();
+            while expired < closed && total > max_bytes {
+                total -= self.segments[expired].size();
+                expired += 1;
+            }
+        }
+        if expired == 0 {
+            return Ok(0);
+        }
+        let first_kept = self.segments[expired].id();
+        for segment in self.segments.drain(..expired) {
+            segment.remove()?;
+        }
+        // positions are indexes into the segments so shift them to the remaining ones,
+        // a reader that was inside a deleted segment continues from the new log start
+        for group in self.groups.values_mut() {
+            if (group.position as usize) < expired {
+                group.position = 0;
+                group.offset = 0;
+            } else {
+                group.position -= expired as u32;
+            }
+            group.in_flight.retain(|id, _| id.segment >= first_kept);
+        }
+        self.wposition -= expired as u32;
+        self.save_group_offsets()?;
+        Ok(expired)
+    }
+
+    /// Drop every message of the queue, offsets keep counting from where they were.
+    /// Returns the number of messages removed
+    pub fn purge(&mut self) -> Result<u64, StorageError> {
+        self.truncate_before(self.next_offset())
+    }
+    /// Drop the messages before `offset`, whole segments are deleted and the segment
+    /// holding `offset` is rewritten to start at it. Consumer groups that were behind
+    /// continue from the new log start, messages in flight in the rewritten segment get
+    /// new ids like after compaction. Returns the number of messages removed
+    pub fn truncate_before(&mut self, offset: u64) -> Result<u64, StorageError> {
+        if self.deleted {
+            return Err(StorageError::Deleted);
+        }
+        let offset = offset.min(self.next_offset());
+        if offset <= self.start_offset() {
+            return Ok(0);
+        }
+        let messages = self.message_count();
+        // only closed segments can be rewritten
+        let active = self.segments.last().expect("active segment");
+        if active.entries() > 0 && active.base_offset < offset {
+            self.roll_segment()?;
+            self.save_queue_offset()?;
+        }
+        let (dropped, cut) = self.truncation(offset);
+        let first_kept = self.segments[dropped].id();
+        // readers behind the new log start are moved to it, the others keep their record
+        let mut groups = self.groups.clone();
+        for group in groups.values_mut() {
+            if (group.position as usize) < dropped {
+                group.position = 0;
+                group.offset = 0;
+            } else {
+                group.position -= dropped as u32;
+                if group.position == 0 {
+                    group.offset = group.offset.saturating_sub(cut as u32);
+                }
+            }
+            let in_flight = std::mem::take(&mut group.in_flight);
+            for (mut id, attempt) in in_flight {
+                if id.segment < first_kept
+                    || (id.segment == first_kept && id.offset as usize <= cut)
+                {
+                    continue;
+                }
+                if id.segment == first_kept {
+                    id.offset -= cut as u32;
+                }
+                group.in_flight.insert(id, attempt);
+            }
+        }
+        // the new start is persisted before any file is removed, a restart finishes
+        // removing the messages before it
+        for group in groups.values() {
+            group.save(self.windex_offset)?;
+        }
+        self.save_log_start(offset)?;
+        self.groups = groups;
+        self.remove_before(offset)?;
+        // compaction leaves gaps in the offsets so the records are counted
+        Ok(messages - self.message_count())
+    }
+    // the number of segments wholly before `offset` and the index bytes cut from the
+    // front of the first segment left, the active segment is never cut
+    fn truncation(&self, offset: u64) -> (usize, usize) {
+        let closed = self.segments.len() - 1;
+        let mut dropped = 0;
+        while dropped < closed && self.segments[dropped].next_offset <= offset {
+            dropped += 1;
+        }
+        let cut = match self.segments[dropped].position_of(offset) {
+            Some(position) if dropped < closed => position - ENTRY_SIZE,
+            _ => 0,
+        };
+        (dropped, cut)
+    }
+    // remove the records before `offset` from disk. Every segment before it leaves the
+    // log even when removing its files fails, the first error is reported after. A
+    // rewrite of the cut segment a crash rolled back is made again on restart
+    fn remove_before(&mut self, offset: u64) -> Result<(), StorageError> {
+        let (dropped, cut) = self.truncation(offset);
+        let mut result = Ok(());
+        for segment in self.segments.drain(..dropped) {
+            if let Err(e) = segment.remove() {
+                result = result.and(Err(e));
+            }
+        }
+        self.wposition -= dropped as u32;
+        if cut > 0 {
+            let segment = &mut self.segments[0];
+            let records = (cut / ENTRY_SIZE..segment.entries())
+                .map(|n| {
+                    let entry = segment.entry(n);
+                    segment
+                        .read_at((n + 1) * ENTRY_SIZE)
+                        .map(|data| (entry, data))
+                })
+                .collect::<Result<Vec<_>, _>>();
+            result = result.and(records.and_then(|records| segment.rewrite(&records)));
+        }
+        result
+    }
+
+    pub fn compaction(&self) -> Option<CompactionPolicy> {
+        self.compaction
+    }
+    pub fn set_compaction(&mut self, policy: Option<CompactionPolicy>) {
+        self.compaction = policy;
+        self.settings_changed();
+    }
+    /// Rewrite the closed segments keeping only the latest record of every key, `key_of`
+    /// extracts the key from a stored record and records without one are always kept.
+    /// Returns the number of records removed
+    pub fn compact<F>(&mut self, key_of: F) -> Result<usize, StorageError>
+    where
+        F: Fn(&[u8]) -> Option<RecordKey>,
+    {
+        let Some(policy) = self.compaction.filter(|_| !self.deleted) else {
+            return Ok(0);
+        };
+        let closed = self.segments.len() - 1;
+        // the active segment is scanned too so its records supersede older ones
+        let mut latest: HashMap<Vec<u8>, (usize, usize)> = HashMap::new();
+        for (idx, segment) in self.segments.iter_mut().enumerate() {
+            for position in segment.positions() {
+                if let Some(record) = key_of(&segment.read_at(position)?) {
+                    latest.insert(record.key, (idx, position));
+                }
+            }
+        }
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for idx in 0..closed {
+            let tombstones_expired = now
+                .duration_since(self.segments[idx].newest_record())
+                .map(|age| age >= policy.tombstone_grace)
+                .unwrap_or(false);
+            let segment = &mut self.segments[idx];
+            let positions = segment.positions();
+            let mut kept = Vec::with_capacity(positions.len());
+            for position in positions.iter().copied() {
+                let data = segment.read_at(position)?;
+                let keep = match key_of(&data) {
+                    Some(record) => {
+                        latest.get(&record.key) == Some(&(idx, position))
+                            && !(record.tombstone && tombstones_expired)
+                    }
+                    None => true,
+                };
+                if keep {
+                    let entry = segment.entry(position / ENTRY_SIZE - 1);
+                    kept.push((position, entry, data));
+                }
+            }
+            if kept.len() == positions.len() {
+                continue;
+            }
+            removed += positions.len() - kept.len();
+            // keep the readers on the same record by counting what survived before it
+            let segment_id = segment.id();
+            let kept_offset = |offset: u32| {
+                let read = kept
+                    .iter()
+                    .filter(|(position, _, _)| *position <= offset as usize)
+                    .count();
+                (read * ENTRY_SIZE) as u32
+            };
+            for group in self.groups.values_mut() {
+                if group.position as usize == idx {
+                    group.offset = kept_offset(group.offset);
+                }
+                let in_flight = std::mem::take(&mut group.in_flight);
+                for (mut id, attempt) in in_flight {
+                    if id.segment == segment_id {
+                        if !kept
+                            .iter()
+                            .any(|(position, _, _)| *position == id.offset as usize)
+                        {
+                            continue;
+                        }
+                        id.offset = kept_offset(id.offset);
+                    }
+                    group.in_flight.insert(id, attempt);
+                }
+            }
+            let records: Vec<(Entry, Vec<u8>)> = kept
+                .into_iter()
+                .map(|(_, entry, data)| (entry, data))
+                .collect();
+            segment.rewrite(&records)?;
+        }
+        if removed > 0 {
+            self.save_group_offsets()?;
+        }
+        Ok(removed)
     }
 
     /// Restore data from disk by loading all segments from the directory
-    pub fn restore_from_disk(segment_size: u64, dir_path: &str) -> Result<Vec<Self>, StorageError> {
+    pub fn restore_from_disk(config: LogConfig, dir_path: &str) -> Result<Vec<Self>, StorageError> {
         let path = PathBuf::from(&dir_path);
         let mut _queue_name = String::new();
         let mut logs = Vec::new();
@@ -297,33 +1194,76 @@ impl CommitLog {
                 if path.is_dir() {
                     let sub_dir = path.strip_prefix(dir_path).unwrap();
                     _queue_name = sub_dir.to_owned().to_str().unwrap().to_string();
-                    let offsets_path = format!("{}{_queue_name}/{}", dir_path, "offsets");
-                    let mut file = OpenOptions::new()
-                        .create(true)
-                        .read(true)
-                        .write(true)
-                        .open(format!("{offsets_path}/{}", _queue_name))
-                        .unwrap();
-                    let mut buf = [0; 12];
-                    file.read(&mut buf).unwrap();
-                    let tracker = Tracker::from_bytes(&buf);
+                    check_format(&path, &_queue_name)?;
+                    finish_rewrites(&path)?;
+                    let offsets_path = path.join("offsets");
+                    let (group, tracker) = ConsumerGroup::open(&offsets_path, &_queue_name)?;
+                    let mut groups = HashMap::from([(_queue_name.clone(), group)]);
+                    for group_entry in fs::read_dir(&offsets_path)? {
+                        let group_name = group_entry?.file_name().to_string_lossy().to_string();
+                        if let hash_map::Entry::Vacant(vacant) = groups.entry(group_name) {
+                            let (group, _) = ConsumerGroup::open(&offsets_path, vacant.key())?;
+                            vacant.insert(group);
+                        }
+                    }
+                    // queues written before their settings were persisted get the
+                    // default config
+                    let settings = read_settings(&path)?;
+                    let QueueSettings { created_at, config } =
+                        settings.unwrap_or_else(|| QueueSettings {
+                            created_at: fs::metadata(&offsets_path)
+                                .and_then(|metadata| metadata.created())
+                                .unwrap_or_else(|_| SystemTime::now())
+                                .duration_since(SystemTime::UNIX_EPOCH)
+                                .map(|created| created.as_millis() as u64)
+                                .unwrap_or(0),
+                            config,
+                        });
                     let segments = load_segments_from_disk(
                         path.to_str().expect("storage path").to_string(),
                         tracker.last_write_offset as usize,
+                        config,
                     );
                     vec_segments.extend(segments);
                     let total_segments = vec_segments.len();
-                    let log = CommitLog {
+                    let mut log = CommitLog {
                         name: _queue_name.to_string(),
                         segments: vec_segments,
-                        segment_size,
+                        segment_size: config.segment_size,
+                        index_size: config.index_size,
+                        retention: config.retention,
+                        compaction: config.compaction,
+                        max_deliveries: config.max_deliveries,
+                        dead_letters: Vec::new(),
                         dir_path: path,
-                        rposition: tracker.position,
-                        queue_pos: file,
-                        rindex_offset: tracker.offset,
+                        groups,
+                        durability: config.durability,
+                        max_message_size: config.max_message_size,
+                        created_at,
+                        unsynced: 0,
+                        last_sync: Instant::now(),
+                        deleted: false,
                         wposition: (total_segments - 1) as u32,
                         windex_offset: tracker.last_write_offset,
                     };
+                    // the marker goes before anything is written so a crash from here
+                    // on is recovered on the next restart
+                    let marker = log.dir_path.join(CLEAN_SHUTDOWN);
+                    if marker.exists() {
+                        fs::remove_file(&marker)?;
+                        File::open(&log.dir_path)?.sync_all()?;
+                    } else {
+                        log.recover()?;
+                    }
+                    let log_start = read_log_start(&log.dir_path);
+                    log.chain_message_offsets(log_start);
+                    // a truncate interrupted after persisting the new start is finished
+                    if let Some(start) = log_start {
+                        log.remove_before(start)?;
+                    }
+                    if settings.is_none() {
+                        log.save_settings()?;
+                    }
                     logs.push(log);
                 }
             }
@@ -331,25 +1271,278 @@ impl CommitLog {
         }
     }
 
+    /// Validates the last segment against its log file after a restart, truncating
+    /// any partially written records and rewriting the tracker to match
+    fn recover(&mut self) -> Result<(), StorageError> {
+        let last = self.segments.len() - 1;
+        let segment = &mut self.segments[last];
+        let recovered_offset = segment.recover(self.windex_offset as usize)? as u32;
+        if recovered_offset != self.windex_offset {
+            warn!(
+                "WARN: Recovered queue {} segment {} to index offset {} (tracker had {})",
+                self.name, last, recovered_offset, self.windex_offset
+            );
+        }
+        self.windex_offset = recovered_offset;
+        let last_id = segment.id();
+        let first_id = self.segments[0].id();
+        for group in self.groups.values_mut() {
+            if group.position as usize > last {
+                group.position = last as u32;
+                group.offset = recovered_offset;
+            } else if group.position as usize == last && group.offset > recovered_offset {
+                group.offset = recovered_offset;
+            }
+            // forget messages in flight that no longer exist on disk
+            group.in_flight.retain(|id, _| {
+                id.segment >= first_id
+                    && id.segment <= last_id
+                    && !(id.segment == last_id && id.offset > recovered_offset)
+            });
+        }
+        self.save_group_offsets()?;
+        Ok(())
+    }
+
+    // empty segments continue the message offsets of the segment before them
+    fn chain_message_offsets(&mut self, log_start: Option<u64>) {
+        let first = &mut self.segments[0];
+        if let Some(start) = log_start.filter(|_| first.entries() == 0) {
+            first.base_offset = start;
+            first.next_offset = start;
+        }
+        for idx in 1..self.segments.len() {
+            if self.segments[idx].entries() == 0 {
+                let (next_offset, max_timestamp) = (
+                    self.segments[idx - 1].next_offset,
+                    self.segments[idx - 1].max_timestamp,
+                );
+                self.segments[idx].base_offset = next_offset;
+                self.segments[idx].next_offset = next_offset;
+                self.segments[idx].max_timestamp = max_timestamp;
+            }
+        }
+    }
+
+    /// reads the next message for the default consumer group
     pub fn read(&mut self) -> Result<Vec<u8>, StorageError> {
-        let len = self.segments.len() - 1;
-        let segment_offset = self.segments[self.rposition as usize].log.index.offset as u32;
-        if self.rindex_offset >= segment_offset && self.rposition == len as u32 {
-            self.rindex_offset = segment_offset;
-            return Err(StorageError::LogIndexOutofBound);
+        let group = self.name.clone();
+        self.read_group(&group)
+    }
+    /// reads the next message for a consumer group, a group reading for the first
+    /// time starts from the beginning of the log. The message is acknowledged as
+    /// soon as it is read
+    pub fn read_group(&mut self, group: &str) -> Result<Vec<u8>, StorageError> {
+        self.read_group_record(group).map(|(_, data)| data)
+    }
+    /// reads up to `max_messages` messages for a consumer group at once, they are
+    /// acknowledged as soon as they are read. Returns them with their message offsets
+    pub fn read_group_batch(
+        &mut self,
+        group: &str,
+        max_messages: u32,
+    ) -> Result<Vec<(u64, Vec<u8>)>, StorageError> {
+        self.open_group(group)?;
+        let consumer = self.groups.get_mut(group).expect("consumer group");
+        let mut ids = Vec::new();
+        while ids.len() < max_messages as usize {
+            match consumer.advance(&self.segments) {
+                Ok(id) => ids.push(id),
+                Err(StorageError::LogIndexOutofBound) if !ids.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        consumer.save(self.windex_offset)?;
+        self.sync_tracker(group)?;
+        let mut records = Vec::with_capacity(ids.len());
+        for id in ids {
+            let segment = self
+                .segments
+                .iter_mut()
+                .find(|s| s.id() == id.segment)
+                .ok_or(StorageError::SegmentNotFound)?;
+            let message_offset = segment
+                .entry(id.offset as usize / ENTRY_SIZE - 1)
+                .message_offset;
+            records.push((message_offset, segment.read_at(id.offset as usize)?));
+        }
+        Ok(records)
+    }
+    /// like `read_group` but also returns the address of the message
+    pub fn read_group_record(&mut self, group: &str) -> Result<(RecordId, Vec<u8>), StorageError> {
+        self.open_group(group)?;
+        let consumer = self.groups.get_mut(group).expect("consumer group");
+        let id = consumer.advance(&self.segments)?;
+        consumer.save(self.windex_offset)?;
+        self.sync_tracker(group)?;
+        Ok((id, self.read_record(id)?))
+    }
+    /// hands out the next message to a consumer group and keeps it in flight until it
+    /// is acknowledged, messages that are nacked or not acknowledged within the
+    /// visibility timeout are handed out again before any newer message
+    pub fn deliver(
+        &mut self,
+        group: &str,
+        visibility_timeout: Duration,
+    ) -> Result<(RecordId, Vec<u8>), StorageError> {
+        let now = Instant::now();
+        self.open_group(group)?;
+        let consumer = self.groups.get_mut(group).expect("consumer group");
+        let mut exhausted = Vec::new();
+        let mut redelivery = None;
+        for (id, attempt) in consumer.in_flight.iter_mut() {
+            if attempt.visible_at > now {
+                continue;
+            }
+            match self.max_deliveries {
+                // stays in flight until it was moved to the dead letter queue, when that
+                // fails it's handed to `take_dead_letters` again after the timeout
+                Some(max) if attempt.deliveries >= max => {
+                    attempt.visible_at = now + visibility_timeout;
+                    exhausted.push((*id, attempt.deliveries));
+                }
+                _ => {
+                    redelivery = Some((*id, attempt.deliveries));
+                    break;
+                }
+            }
+        }
+        let (id, deliveries) = match redelivery {
+            Some(redelivery) => redelivery,
+            None => match consumer.advance(&self.segments) {
+                Ok(id) => (id, 0),
+                Err(e) => {
+                    consumer.save(self.windex_offset)?;
+                    self.sync_tracker(group)?;
+                    self.dead_letter(group, exhausted)?;
+                    return Err(e);
+                }
+            },
+        };
+        consumer.in_flight.insert(
+            id,
+            InFlight {
+                visible_at: now + visibility_timeout,
+                deliveries: deliveries + 1,
+            },
+        );
+        consumer.save(self.windex_offset)?;
+        self.sync_tracker(group)?;
+        self.dead_letter(group, exhausted)?;
+        Ok((id, self.read_record(id)?))
+    }
+    // queue the messages of a group that ran out of delivery attempts
+    fn dead_letter(
+        &mut self,
+        group: &str,
+        exhausted: Vec<(RecordId, u32)>,
+    ) -> Result<(), StorageError> {
+        for (id, deliveries) in exhausted {
+            let message = self.read_record(id)?;
+            self.dead_letters.push(DeadLetter {
+                group: group.to_owned(),
+                id,
+                deliveries,
+                message,
+            });
+        }
+        Ok(())
+    }
+    /// messages that were handed out `max_deliveries` times without being acknowledged,
+    /// they are no longer handed out and should be moved to a dead letter queue. Each
+    /// stays in flight until it is acknowledged with `ack` once it was moved
+    pub fn take_dead_letters(&mut self) -> Vec<DeadLetter> {
+        std::mem::take(&mut self.dead_letters)
+    }
+    pub fn max_deliveries(&self) -> Option<u32> {
+        self.max_deliveries
+    }
+    pub fn set_max_deliveries(&mut self, max_deliveries: Option<u32>) {
+        self.max_deliveries = max_deliveries;
+        self.settings_changed();
+    }
+    /// acknowledge a message handed out by `deliver` so it is never handed out again
+    pub fn ack(&mut self, group: &str, id: RecordId) -> Result<(), StorageError> {
+        self.open_group(group)?;
+        let consumer = self.groups.get_mut(group).expect("consumer group");
+        if consumer.in_flight.remove(&id).is_none() {
+            return Err(StorageError::NotInFlight);
+        }
+        consumer.save(self.windex_offset)?;
+        self.sync_tracker(group)
+    }
+    /// reject a message handed out by `deliver` making it visible again right away
+    pub fn nack(&mut self, group: &str, id: RecordId) -> Result<(), StorageError> {
+        self.open_group(group)?;
+        let consumer = self.groups.get_mut(group).expect("consumer group");
+        match consumer.in_flight.get_mut(&id) {
+            Some(attempt) => {
+                attempt.visible_at = Instant::now();
+                Ok(())
+            }
+            None => Err(StorageError::NotInFlight),
+        }
+    }
+    /// names of the consumer groups reading from the queue
+    pub fn groups(&self) -> Vec<String> {
+        self.groups.keys().cloned().collect()
+    }
+    /// bytes of record data held by the queue's segments
+    pub fn size(&self) -> u64 {
+        self.segments.iter().map(|s| s.size()).sum()
+    }
+    /// number of messages still stored, compaction and retention lower it
+    pub fn message_count(&self) -> u64 {
+        self.segments.iter().map(|s| s.entries() as u64).sum()
+    }
+    /// number of stored messages a consumer group has not read yet, None for a group
+    /// that never read from the queue
+    pub fn lag(&self, group: &str) -> Option<u64> {
+        let consumer = self.groups.get(group)?;
+        let position = consumer.position as usize;
+        let read = consumer.offset as usize / ENTRY_SIZE;
+        let unread = self.segments[position].entries().saturating_sub(read)
+            + self.segments[position + 1..]
+                .iter()
+                .map(|s| s.entries())
+                .sum::<usize>();
+        Some(unread as u64)
+    }
+    /// number of messages handed out to a consumer group that are not acknowledged yet
+    pub fn in_flight(&self, group: &str) -> usize {
+        self.groups
+            .get(group)
+            .map_or(0, |consumer| consumer.in_flight.len())
+    }
+    // opens the tracker of a consumer group the first time it is used
+    fn open_group(&mut self, group: &str) -> Result<(), StorageError> {
+        if !self.groups.contains_key(group) {
+            let (consumer, _) = ConsumerGroup::open(&self.dir_path.join("offsets"), group)?;
+            self.groups.insert(group.to_owned(), consumer);
         }
-        if self.rindex_offset >= segment_offset && self.rposition != len as u32 {
-            self.rposition += 1;
-            self.rindex_offset = 0;
+        Ok(())
+    }
+    // read the record at the given address
+    fn read_record(&mut self, id: RecordId) -> Result<Vec<u8>, StorageError> {
+        let segment = self
+            .segments
+            .iter_mut()
+            .find(|s| s.id() == id.segment)
+            .ok_or(StorageError::SegmentNotFound)?;
+        segment.read_at(id.offset as usize)
+    }
+    // persist the last write offset in the default group's tracker
+    fn save_queue_offset(&mut self) -> Result<(), StorageError> {
+        match self.groups.get(&self.name) {
+            Some(group) => group.save(self.windex_offset),
+            None => Ok(()),
         }
-        self.rindex_offset += ENTRY_SIZE as u32;
-        self.save_queue_offset();
-        let segment = &mut self.segments[self.rposition as usize];
-        Ok(segment.read_at(self.rindex_offset as usize)?)
     }
-    fn save_queue_offset(&mut self) {
-        let payload = Tracker::to_bytes(self.rposition, self.rindex_offset, self.windex_offset);
-        self.queue_pos.write_at(&payload, 0).unwrap();
+    fn save_group_offsets(&mut self) -> Result<(), StorageError> {
+        for group in self.groups.values() {
+            group.save(self.windex_offset)?;
+        }
+        Ok(())
     }
     /// reads data stored from a given offset
     pub fn read_at_from_disk(&mut self, position: usize) -> Result<Vec<u8>, StorageError> {
@@ -372,14 +1565,265 @@ impl CommitLog {
     }
 }
 
+// the settings in a queue's meta file, None when there is none. A file that can't be
+// decoded is an error, the queue's settings would be lost if it was replaced
+fn read_settings(dir: &Path) -> Result<Option<QueueSettings>, StorageError> {
+    match fs::read(dir.join(SETTINGS_FILE)) {
+        Ok(data) => match QueueSettings::from_bytes(&data) {
+            Some(settings) => Ok(Some(settings)),
+            None => {
+                warn!(
+                    "WARN: Refusing to load unreadable settings in {}, version {:?} is not {SETTINGS_VERSION}",
+                    dir.display(),
+                    data.first()
+                );
+                Err(StorageError::InvalidSettings)
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_log_start(dir: &Path) -> Option<u64> {
+    let data = fs::read(dir.join(LOG_START_FILE)).ok()?;
+    Some(u64::from_be_bytes(data.get(..8)?.try_into().unwrap()))
+}
+
+// written like the settings, a queue's files are only read once the version is known
+fn write_format(dir: &Path) -> Result<(), StorageError> {
+    let tmp_path = dir.join(format!("{FORMAT_FILE}.tmp"));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&[FORMAT_VERSION])?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, dir.join(FORMAT_FILE))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// brings the files of a queue to FORMAT_VERSION before they are loaded. Queues in
+// a layout this version doesn't know are refused, recovering them with the wrong
+// entry size would truncate their logs
+fn check_format(dir: &Path, queue_name: &str) -> Result<(), StorageError> {
+    let version = match fs::read(dir.join(FORMAT_FILE)) {
+        Ok(data) => data.first().copied().unwrap_or(0),
+        // the meta file was added with version 2, queues without either are older
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if dir.join(SETTINGS_FILE).exists() {
+                write_format(dir)?;
+                FORMAT_VERSION
+            } else {
+                1
+            }
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match version {
+        // finishes a migration that stopped after recording the new version
+        FORMAT_VERSION => finish_migration(dir),
+        1 => migrate_v1(dir, queue_name),
+        version => {
+            warn!(
+                "WARN: Refusing to load queue {queue_name}, its format version {version} is not {FORMAT_VERSION}"
+            );
+            Err(StorageError::UnsupportedFormat(version))
+        }
+    }
+}
+
+// the files a migration rewrote, in the queue's directory and its offsets
+fn migration_files(dir: &Path) -> Result<Vec<PathBuf>, StorageError> {
+    let mut files = Vec::new();
+    for dir in [dir.to_path_buf(), dir.join("offsets")] {
+        if !dir.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == MIGRATE_EXTENSION)
+            {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+// finish or roll back the segment rewrites a crash interrupted. A rewritten index left
+// without its log was committed and is moved in place, the files of a rewrite whose
+// log wasn't renamed yet are removed
+fn finish_rewrites(dir: &Path) -> Result<(), StorageError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == COMPACT_EXTENSION)
+        {
+            let target = path.with_extension("");
+            let committed = target
+                .extension()
+                .is_some_and(|extension| extension == "idx")
+                && !target.with_extension("log.compact").exists();
+            files.push((path, target, committed));
+        }
+    }
+    if files.is_empty() {
+        return Ok(());
+    }
+    for (path, target, committed) in files {
+        if committed {
+            warn!(
+                "WARN: Finishing the interrupted rewrite of {}",
+                target.display()
+            );
+            fs::rename(&path, &target)?;
+        } else {
+            warn!(
+                "WARN: Discarding the interrupted rewrite of {}",
+                target.display()
+            );
+            fs::remove_file(&path)?;
+        }
+    }
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn finish_migration(dir: &Path) -> Result<(), StorageError> {
+    let files = migration_files(dir)?;
+    if files.is_empty() {
+        return Ok(());
+    }
+    for path in files {
+        fs::rename(&path, path.with_extension(""))?;
+    }
+    File::open(dir)?.sync_all()?;
+    File::open(dir.join("offsets"))?.sync_all()?;
+    Ok(())
+}
+
+/// Rewrites the index and tracker of a queue stored with 8 byte index entries. The
+/// records are kept as they are, their checksums are computed from the data and the
+/// segment's modification time stands in for when they were appended. Nothing is
+/// replaced until every segment was read, a queue whose index doesn't chain through
+/// its log is refused
+fn migrate_v1(dir: &Path, queue_name: &str) -> Result<(), StorageError> {
+    let refuse = |reason: String| {
+        warn!("WARN: Refusing to migrate queue {queue_name}: {reason}");
+        StorageError::UnsupportedFormat(1)
+    };
+    // an earlier attempt that didn't get to record the version starts over
+    for path in migration_files(dir)? {
+        fs::remove_file(path)?;
+    }
+    let offsets_path = dir.join("offsets");
+    fs::create_dir_all(&offsets_path)?;
+    let tracker_path = offsets_path.join(queue_name);
+    let mut buf = match fs::read(&tracker_path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    // later trackers carry the messages in flight after the 12 bytes
+    if buf.len() > 12 {
+        return Err(refuse(format!("its tracker has {} bytes", buf.len())));
+    }
+    buf.resize(12, 0);
+    let tracker = Tracker::from_bytes(&buf);
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "log") {
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<u32>().ok());
+            ids.push(id.ok_or_else(|| refuse(format!("unexpected file {}", path.display())))?);
+        }
+    }
+    ids.sort();
+    let mut message_offset = 0;
+    let mut last_write_offset = 0;
+    for (n, id) in ids.iter().enumerate() {
+        let log_path = dir.join(format!("{id:0>12}.log"));
+        let index = fs::read(dir.join(format!("{id:0>12}.idx")))?;
+        let data = fs::read(&log_path)?;
+        let timestamp = fs::metadata(&log_path)?
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|modified| modified.as_millis() as u64)
+            .unwrap_or(0);
+        let last = n == ids.len() - 1;
+        let mut entries = Vec::new();
+        let mut end = 0;
+        for entry in index.chunks_exact(V1_ENTRY_SIZE) {
+            let offset = u32::from_be_bytes(entry[..4].try_into().unwrap()) as usize;
+            let size = u32::from_be_bytes(entry[4..8].try_into().unwrap()) as usize;
+            // the index of the last segment was preallocated, its entries end with the log
+            if (last && end == data.len()) || offset != end || offset + size > data.len() {
+                break;
+            }
+            let record = &data[offset..offset + size];
+            entries.extend(
+                Entry::new(
+                    offset as u32,
+                    size as u32,
+                    crc32c(record),
+                    message_offset,
+                    timestamp,
+                )
+                .as_bytes(),
+            );
+            message_offset += 1;
+            end = offset + size;
+        }
+        let indexed = entries.len() / ENTRY_SIZE;
+        // the tracker of the last segment lags up to one entry behind its index, a
+        // record whose data was cut short is left for recovery to truncate
+        let complete = if last {
+            let tracked = tracker.last_write_offset as usize;
+            (tracked..=tracked + V1_ENTRY_SIZE).contains(&(indexed * V1_ENTRY_SIZE))
+        } else {
+            indexed * V1_ENTRY_SIZE == index.len() && end == data.len()
+        };
+        if !complete {
+            return Err(refuse(format!(
+                "the index of segment {id} covers {end} of {} bytes",
+                data.len()
+            )));
+        }
+        if last {
+            last_write_offset = entries.len() as u32;
+        }
+        let mut file = File::create(dir.join(format!("{id:0>12}.idx.{MIGRATE_EXTENSION}")))?;
+        file.write_all(&entries)?;
+        file.sync_all()?;
+    }
+    let scale = |offset: u32| offset / V1_ENTRY_SIZE as u32 * ENTRY_SIZE as u32;
+    let payload = Tracker::to_bytes(tracker.position, scale(tracker.offset), last_write_offset);
+    let mut file = File::create(offsets_path.join(format!("{queue_name}.{MIGRATE_EXTENSION}")))?;
+    file.write_all(&payload)?;
+    file.sync_all()?;
+    File::open(dir)?.sync_all()?;
+    File::open(&offsets_path)?.sync_all()?;
+    write_format(dir)?;
+    finish_migration(dir)?;
+    warn!(
+        "WARN: Migrated queue {queue_name} to format version {FORMAT_VERSION}, {message_offset} records"
+    );
+    Ok(())
+}
+
 /// Reads the entire data stored on disk and loads it as a vector of segments
-pub fn load_segments_from_disk(path: String, l_offset: usize) -> Vec<Segment> {
+pub fn load_segments_from_disk(path: String, l_offset: usize, config: LogConfig) -> Vec<Segment> {
     let mut log_file: Vec<u32> = Vec::new();
     let mut segments: Vec<Segment> = Vec::new();
     for entry in fs::read_dir(&path).unwrap() {
         let entry = entry.unwrap();
         let path = entry.path();
-        if !path.is_dir() && path.extension().unwrap().to_str().unwrap() == "log" {
+        if !path.is_dir() && path.extension().is_some_and(|extension| extension == "log") {
             let log_id = path
                 .file_stem()
                 .unwrap()
@@ -398,11 +1842,13 @@ pub fn load_segments_from_disk(path: String, l_offset: usize) -> Vec<Segment> {
         let idx_path = format!("{path}/{log:0>12}.idx");
         let idx_f = File::open(&idx_path).unwrap();
         let metadata = idx_f.metadata().unwrap();
-        let mut segment_size = metadata.len();
+        let mut index_len = metadata.len();
         let mut last_entry_offset = None;
 
         if log == last_log {
-            segment_size = SEGMENT_SIZE as u64;
+            // the segment being written to keeps the room it was created with when the
+            // configured index size shrank since
+            index_len = index_len.max(config.index_size);
             last_entry_offset = Some(l_offset as u32);
         }
         let segment = load_segment(
@@ -410,7 +1856,8 @@ pub fn load_segments_from_disk(path: String, l_offset: usize) -> Vec<Segment> {
             log_path,
             idx_path,
             log,
-            segment_size,
+            index_len,
+            config.segment_size,
             last_entry_offset,
         )
         .expect("a segment");
@@ -425,6 +1872,7 @@ fn load_segment(
     index_path: String,
     log_id: u32,
     index_len: u64,
+    segment_size: u64,
     mut last_entry_offset: Option<u32>,
 ) -> Result<Segment, StorageError> {
     let idx_f = File::open(index_path)?;
@@ -438,7 +1886,7 @@ fn load_segment(
         dir_path,
         log_id,
         log_md.len(),
-        SEGMENT_SIZE as u64,
+        segment_size,
         last_entry_offset.unwrap() as u64,
         false,
         index_len,
@@ -447,16 +1895,24 @@ fn load_segment(
 }
 
 impl Segment {
-    pub fn new(dir: &str, log_name: u32, segment_size: u64) -> Segment {
+    pub fn new(
+        dir: &str,
+        log_name: u32,
+        segment_size: u64,
+        index_size: u64,
+    ) -> Result<Segment, StorageError> {
         let path = PathBuf::from(dir);
-        Segment {
-            log: Log::new(&path, log_name).expect("creating file"),
+        Ok(Segment {
+            log: Log::new(&path, log_name, index_size)?,
             path,
             base_offset: 0,
+            next_offset: 0,
+            max_timestamp: 0,
             current_offset: 0,
             segment_size,
             closed: false,
-        }
+            dirty: false,
+        })
     }
     // load existing segment
     fn load(
@@ -469,36 +1925,86 @@ impl Segment {
         index_len: u64,
     ) -> Segment {
         let path = PathBuf::from(dir);
-        Segment {
+        let mut segment = Segment {
             log: Log::load(&path, log_name, index_offset, index_len).expect("creating file"),
             path,
             base_offset: 0,
+            next_offset: 0,
+            max_timestamp: 0,
             current_offset,
             segment_size,
             closed,
+            dirty: false,
+        };
+        segment.load_message_offsets();
+        segment
+    }
+    // read the message offsets of the first and last record, empty segments keep theirs
+    fn load_message_offsets(&mut self) {
+        let entries = self.entries();
+        if entries > 0 {
+            let last = self.entry(entries - 1);
+            self.base_offset = self.entry(0).message_offset;
+            self.next_offset = last.message_offset + 1;
+            self.max_timestamp = last.timestamp;
         }
     }
-    pub fn append_data(&mut self, data: &[u8]) -> Result<(), StorageError> {
-        match self.check_split(data.len() as u64) {
-            Ok(_) => {
-                let entry = Entry::new(self.current_offset as u32, data.len() as u32);
-                self.log.write(data, &entry)?;
-                self.current_offset();
-                self.flush()?;
-                Ok(())
-            }
-            Err(e) => Err(e),
+    /// append a record to the segment and return the message offset it was given
+    pub fn append_data(&mut self, data: &[u8]) -> Result<u64, StorageError> {
+        self.append_batch(&[data])
+    }
+    /// append records with a single flush and return the message offset of the first
+    /// one, nothing is written when they don't all fit in the segment
+    pub fn append_batch(&mut self, records: &[&[u8]]) -> Result<u64, StorageError> {
+        let size = records.iter().map(|data| data.len() as u64).sum();
+        self.check_split(size, records.len())?;
+        let first_offset = self.next_offset;
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|now| now.as_millis() as u64)
+            .unwrap_or(0)
+            .max(self.max_timestamp);
+        for data in records {
+            let entry = Entry::new(
+                self.current_offset as u32,
+                data.len() as u32,
+                crc32c(data),
+                self.next_offset,
+                timestamp,
+            );
+            self.log.write(data, &entry)?;
+            self.next_offset += 1;
+            self.current_offset();
         }
+        self.max_timestamp = timestamp;
+        self.dirty = true;
+        self.flush()?;
+        Ok(first_offset)
     }
     fn flush(&mut self) -> Result<(), StorageError> {
         self.log.flush()?;
         Ok(())
     }
+    // fsync the log and its index
+    fn sync(&mut self) -> Result<(), StorageError> {
+        self.log.sync()?;
+        self.dirty = false;
+        Ok(())
+    }
+    /// drops any torn records at the tail of the segment and returns the
+    /// index offset after the last valid entry
+    fn recover(&mut self, tracked_offset: usize) -> Result<usize, StorageError> {
+        let offset = self.log.recover(tracked_offset)?;
+        self.current_offset();
+        self.load_message_offsets();
+        Ok(offset)
+    }
     fn current_offset(&mut self) {
         self.current_offset = self.log.writer.position;
     }
-    fn check_split(&self, entry_size: u64) -> Result<bool, StorageError> {
-        match (self.current_offset + entry_size) > self.segment_size {
+    fn check_split(&self, entry_size: u64, entries: usize) -> Result<bool, StorageError> {
+        let index_full = self.log.index.offset + entries * ENTRY_SIZE > self.log.index.max_size;
+        match (self.current_offset + entry_size) > self.segment_size || index_full {
             true => Err(StorageError::NoSpaceLeft),
             false => Ok(true),
         }
@@ -522,23 +2028,160 @@ impl Segment {
     fn close(&mut self) {
         self.closed = true
     }
+    // the position this segment's files are named after
+    fn id(&self) -> u32 {
+        self.log.id
+    }
+    // bytes of record data held by the segment
+    fn size(&self) -> u64 {
+        self.current_offset
+    }
+    fn log_path(&self) -> PathBuf {
+        self.path.join(format!("{:0>12}.log", self.id()))
+    }
+    fn index_path(&self) -> PathBuf {
+        self.path.join(format!("{:0>12}.idx", self.id()))
+    }
+    // the time the newest record was appended to the segment
+    fn newest_record(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.max_timestamp)
+    }
+    // index positions of every record in the segment
+    fn positions(&self) -> Vec<usize> {
+        (1..=self.entries())
+            .map(|entry| entry * ENTRY_SIZE)
+            .collect()
+    }
+    // number of records in the segment
+    fn entries(&self) -> usize {
+        self.log.index.offset / ENTRY_SIZE
+    }
+    // the nth entry of the segment's index
+    fn entry(&self, n: usize) -> Entry {
+        Entry::from_bytes(&self.log.index.mmap[n * ENTRY_SIZE..(n + 1) * ENTRY_SIZE])
+    }
+    // index position of the first record at or after the given message offset
+    fn position_of(&self, message_offset: u64) -> Option<usize> {
+        let entries = self.entries();
+        let (mut low, mut high) = (0, entries);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.entry(mid).message_offset < message_offset {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        (low < entries).then_some((low + 1) * ENTRY_SIZE)
+    }
+    // message offset of the first record appended at or after the given time, entry
+    // timestamps never decrease so the index is searched like by message offset
+    fn offset_for_time(&self, timestamp: u64) -> Option<u64> {
+        let entries = self.entries();
+        let (mut low, mut high) = (0, entries);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.entry(mid).timestamp < timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        (low < entries).then(|| self.entry(low).message_offset)
+    }
+    // replace the files of a closed segment with the given records, the new files are
+    // written next to the old ones and renamed over them once synced. Renaming the log
+    // commits the rewrite, a crash after it is finished by `finish_rewrites`
+    fn rewrite(&mut self, records: &[(Entry, Vec<u8>)]) -> Result<(), StorageError> {
+        let log_path = self.log_path();
+        let index_path = self.index_path();
+        let compact_log_path = log_path.with_extension("log.compact");
+        let compact_index_path = index_path.with_extension("idx.compact");
+        let mut log_file = File::create(&compact_log_path)?;
+        let mut index_file = File::create(&compact_index_path)?;
+        let mut offset = 0u32;
+        for (kept, data) in records {
+            let entry = Entry::new(
+                offset,
+                data.len() as u32,
+                crc32c(data),
+                kept.message_offset,
+                kept.timestamp,
+            );
+            log_file.write_all(data)?;
+            index_file.write_all(&entry.as_bytes())?;
+            offset += data.len() as u32;
+        }
+        log_file.sync_all()?;
+        index_file.sync_all()?;
+        // every rename is synced before the next so they can't reach the disk reordered
+        let dir = File::open(&self.path)?;
+        dir.sync_all()?;
+        fs::rename(&compact_log_path, &log_path)?;
+        dir.sync_all()?;
+        fs::rename(&compact_index_path, &index_path)?;
+        dir.sync_all()?;
+        let index_len = (records.len() * ENTRY_SIZE) as u64;
+        let (base_offset, next_offset, max_timestamp) =
+            (self.base_offset, self.next_offset, self.max_timestamp);
+        *self = Segment::load(
+            self.path.to_str().expect("segment path"),
+            self.id(),
+            offset as u64,
+            self.segment_size,
+            index_len,
+            true,
+            index_len,
+        );
+        if records.is_empty() {
+            self.base_offset = base_offset;
+            self.next_offset = next_offset;
+            self.max_timestamp = max_timestamp;
+        }
+        Ok(())
+    }
+    // delete the segment's log and index files
+    fn remove(self) -> Result<(), StorageError> {
+        let log_path = self.log_path();
+        let index_path = self.index_path();
+        drop(self.log);
+        fs::remove_file(log_path)?;
+        fs::remove_file(index_path)?;
+        Ok(())
+    }
 }
 impl Entry {
-    fn new(offset: u32, size: u32) -> Entry {
-        Entry { offset, size }
+    fn new(offset: u32, size: u32, crc: u32, message_offset: u64, timestamp: u64) -> Entry {
+        Entry {
+            offset,
+            size,
+            crc,
+            message_offset,
+            timestamp,
+        }
     }
     fn as_bytes(&self) -> Vec<u8> {
-        let mut payload: Vec<u8> = Vec::with_capacity(8);
+        let mut payload: Vec<u8> = Vec::with_capacity(ENTRY_SIZE);
         payload.extend(self.offset.to_be_bytes());
         payload.extend(self.size.to_be_bytes());
+        payload.extend(self.crc.to_be_bytes());
+        payload.extend(self.message_offset.to_be_bytes());
+        payload.extend(self.timestamp.to_be_bytes());
         payload
     }
     fn from_bytes(data: &[u8]) -> Self {
         Entry {
             offset: u32::from_be_bytes(data[..4].try_into().unwrap()),
             size: u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            crc: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+            message_offset: u64::from_be_bytes(data[12..20].try_into().unwrap()),
+            timestamp: u64::from_be_bytes(data[20..28].try_into().unwrap()),
         }
     }
+    // checks the record data against the checksum stored in the entry
+    fn verify(&self, data: &[u8]) -> bool {
+        data.len() == self.size as usize && crc32c(data) == self.crc
+    }
 }
 impl Index {
     #[allow(clippy::ptr_arg)]
@@ -607,7 +2250,7 @@ impl Index {
         if self.roffset >= self.offset {
             Err(StorageError::LogIndexOutofBound)
         } else {
-            self.roffset += 8;
+            self.roffset += ENTRY_SIZE;
             Ok(Entry::from_bytes(
                 &self.mmap[(self.roffset - ENTRY_SIZE)..self.roffset],
             ))
@@ -631,7 +2274,7 @@ impl Index {
 }
 
 impl Log {
-    fn new(dir: &PathBuf, pos: u32) -> Result<Log, StorageError> {
+    fn new(dir: &PathBuf, pos: u32, index_size: u64) -> Result<Log, StorageError> {
         let path = dir.join(format!("{pos:0>12}.log"));
         let offsets_path = format!("{}/{}", dir.as_path().to_str().unwrap(), "offsets");
         fs::create_dir_all(offsets_path).unwrap();
@@ -645,9 +2288,10 @@ impl Log {
             Ok(file) => {
                 let read_file = OpenOptions::new().read(true).open(&path)?;
                 Ok(Log {
+                    id: pos,
                     writer: CursorWriter::new(file, SeekFrom::Start(0))?,
                     reader: CursorReader::new(read_file)?,
-                    index: Index::new(dir, pos, SEGMENT_SIZE), //TODO Index: max_size should be a setting
+                    index: Index::new(dir, pos, index_size as usize),
                 })
             }
             Err(err) => Err(StorageError::IoError(err)),
@@ -659,6 +2303,7 @@ impl Log {
             Ok(file) => {
                 let read_file = OpenOptions::new().read(true).open(&path)?;
                 Ok(Log {
+                    id: pos,
                     writer: CursorWriter::new(file, SeekFrom::End(0))?,
                     reader: CursorReader::new(read_file)?,
                     index: Index::load(dir.to_owned(), pos, offset, index_len as usize),
@@ -673,29 +2318,95 @@ impl Log {
         Ok(())
     }
 
+    // hand buffered writes to the OS so readers see them
     fn flush(&mut self) -> Result<(), StorageError> {
         self.writer.flush()?;
+        Ok(())
+    }
+    fn sync(&mut self) -> Result<(), StorageError> {
+        self.writer.flush()?;
+        self.writer.writer.get_ref().sync_data()?;
         self.index.flush()?;
         Ok(())
     }
+    // walks the index validating every entry against the log file, entries
+    // past the tracked offset that were never written are all zeroes
+    fn recover(&mut self, tracked_offset: usize) -> Result<usize, StorageError> {
+        let log_len = self.writer.seek(SeekFrom::End(0))?;
+        let index_len = self.index.mmap.len();
+        let mut offset = 0;
+        let mut log_end = 0u64;
+        let mut last_message_offset = None;
+        while offset + ENTRY_SIZE <= index_len {
+            let slot = &self.index.mmap[offset..offset + ENTRY_SIZE];
+            if offset >= tracked_offset && slot.iter().all(|b| *b == 0) {
+                break;
+            }
+            let entry = Entry::from_bytes(slot);
+            if entry.offset as u64 != log_end || log_end + entry.size as u64 > log_len {
+                break;
+            }
+            if last_message_offset.is_some_and(|last| entry.message_offset <= last) {
+                break;
+            }
+            last_message_offset = Some(entry.message_offset);
+            if self.read_entry(&entry).is_err() {
+                break;
+            }
+            log_end += entry.size as u64;
+            offset += ENTRY_SIZE;
+        }
+        // clear the torn entries so they are not mistaken for records later
+        let mut torn_end = offset;
+        while torn_end + ENTRY_SIZE <= index_len
+            && self.index.mmap[torn_end..torn_end + ENTRY_SIZE]
+                .iter()
+                .any(|b| *b != 0)
+        {
+            torn_end += ENTRY_SIZE;
+        }
+        self.index.mmap[offset..torn_end].fill(0);
+        self.index.offset = offset;
+        self.index.flush()?;
+        if log_len > log_end {
+            self.writer.writer.get_ref().set_len(log_end)?;
+            self.writer.seek(SeekFrom::End(0))?;
+        }
+        Ok(offset)
+    }
     fn seek_at(&mut self, offset: usize) -> Result<Vec<u8>, StorageError> {
         let entry = self.index.seek_at(offset)?;
-        let mut buf = vec![0u8; entry.size as usize];
-        self.reader.read_at(&mut buf[..], (entry.offset) as u64)?;
-        Ok(buf[..entry.size as usize].to_vec())
+        self.read_entry(&entry)
     }
 
     fn seek_from_start(&mut self) -> Result<Vec<u8>, StorageError> {
         let entry = self.index.seek_from_start()?;
-        let mut buf = vec![0u8; entry.size as usize];
-        self.reader.read_at(&mut buf[..], (entry.offset) as u64)?;
-        Ok(buf.to_vec())
+        self.read_entry(&entry)
     }
     fn seek_next(&mut self, offset: usize) -> Result<Vec<u8>, StorageError> {
         let entry = self.index.seek_after(offset)?;
+        self.read_entry(&entry)
+    }
+    // reads the record an entry points to and verifies its checksum
+    fn read_entry(&mut self, entry: &Entry) -> Result<Vec<u8>, StorageError> {
         let mut buf = vec![0u8; entry.size as usize];
-        self.reader.read_at(&mut buf[..], (entry.offset) as u64)?;
-        Ok(buf.to_vec())
+        let mut read = 0;
+        while read < buf.len() {
+            let size = self
+                .reader
+                .read_at(&mut buf[read..], entry.offset as u64 + read as u64)?;
+            if size == 0 {
+                break;
+            }
+            read += size;
+        }
+        if !entry.verify(&buf[..read]) {
+            return Err(StorageError::CorruptRecord {
+                segment: self.id,
+                offset: entry.offset,
+            });
+        }
+        Ok(buf)
     }
 
     // TODO: Test This when too man files are created and not closed
@@ -708,7 +2419,10 @@ impl Log {
 mod test {
     #![allow(unused_imports)]
 
-    use crate::internal::log::{load_segments_from_disk, CursorReader, Entry, SEGMENT_SIZE};
+    use crate::internal::checksum::crc32c;
+    use crate::internal::log::{
+        load_segments_from_disk, CursorReader, Entry, ENTRY_SIZE, INDEX_SIZE, SEGMENT_SIZE,
+    };
 
     use super::{Segment, StorageError};
     use std::cell::RefCell;
@@ -732,7 +2446,7 @@ mod test {
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .subsec_nanos();
-        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64);
+        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64, INDEX_SIZE as u64).unwrap();
         let data = b"Hello World!";
         seg.append_data(data).unwrap();
         assert_eq!(seg.current_offset, data.len() as u64)
@@ -747,7 +2461,7 @@ mod test {
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .subsec_nanos();
-        let mut seg = Segment::new(PATH, offset, segment_size);
+        let mut seg = Segment::new(PATH, offset, segment_size, INDEX_SIZE as u64).unwrap();
         seg.append_data(data).unwrap();
         seg.append_data(data).unwrap();
     }
@@ -757,17 +2471,20 @@ mod test {
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .subsec_nanos();
-        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64);
+        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64, INDEX_SIZE as u64).unwrap();
         let data = b"Hello World!";
         seg.append_data(data).unwrap();
         seg.append_data(data).unwrap();
         seg.append_data(data).unwrap();
-        let mmap = &seg.log.index.mmap;
+        let crc = crc32c(data);
+        let time = |n| seg.entry(n).timestamp;
         let expected_entries = vec![
-            Entry::new(0, data.len() as u32).as_bytes(),
-            Entry::new(data.len() as u32, data.len() as u32).as_bytes(),
-            Entry::new((data.len() as u32) * 2, data.len() as u32).as_bytes(),
+            Entry::new(0, data.len() as u32, crc, 0, time(0)).as_bytes(),
+            Entry::new(data.len() as u32, data.len() as u32, crc, 1, time(1)).as_bytes(),
+            Entry::new((data.len() as u32) * 2, data.len() as u32, crc, 2, time(2)).as_bytes(),
         ];
+        assert!(time(0) <= time(1) && time(1) <= time(2));
+        let mmap = &seg.log.index.mmap;
         let mut offset = 0;
         for expected in expected_entries {
             let entry_bytes = &mmap[offset..offset + expected.len()];
@@ -776,19 +2493,36 @@ mod test {
         }
     }
     #[test]
+    fn test_offset_for_time_searches_index() {
+        let offset = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64, INDEX_SIZE as u64).unwrap();
+        let data = b"Hello World!";
+        for _ in 0..5 {
+            seg.append_data(data).unwrap();
+        }
+        assert_eq!(seg.offset_for_time(0), Some(0));
+        let third = seg.entry(2).timestamp;
+        let first_at = (0..5).find(|&n| seg.entry(n).timestamp >= third).unwrap();
+        assert_eq!(seg.offset_for_time(third), Some(first_at as u64));
+        assert_eq!(seg.offset_for_time(seg.entry(4).timestamp + 1), None);
+    }
+    #[test]
     fn test_log_read_at() {
         let offset = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .subsec_nanos();
 
-        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64);
+        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64, INDEX_SIZE as u64).unwrap();
         let data = b"Hello World!";
         let data2 = b"Hello World!1";
         seg.append_data(data).unwrap();
         seg.append_data(data2).unwrap();
-        let read_data = seg.read_at(8).unwrap();
-        let read_current_data = seg.read_at(16).unwrap();
+        let read_data = seg.read_at(ENTRY_SIZE).unwrap();
+        let read_current_data = seg.read_at(ENTRY_SIZE * 2).unwrap();
         assert_eq!(read_data, data);
         assert_eq!(read_current_data, data2);
     }
@@ -800,9 +2534,38 @@ mod test {
             .unwrap()
             .subsec_nanos();
 
-        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64);
+        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64, INDEX_SIZE as u64).unwrap();
         let data = b"Hello World!";
         seg.append_data(data).unwrap();
         seg.read_at(0).unwrap();
     }
+    #[test]
+    fn test_log_read_corrupt_record() {
+        let offset = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+
+        let mut seg = Segment::new(PATH, offset, SEGMENT_SIZE as u64, INDEX_SIZE as u64).unwrap();
+        let data = b"Hello World!";
+        seg.append_data(data).unwrap();
+        seg.append_data(data).unwrap();
+        // simulate a torn write by truncating the last record
+        let log_file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(PathBuf::from(PATH).join(format!("{offset:0>12}.log")))
+            .unwrap();
+        log_file.set_len((data.len() * 2 - 4) as u64).unwrap();
+        assert_eq!(seg.read_at(ENTRY_SIZE).unwrap(), data);
+        match seg.read_at(ENTRY_SIZE * 2) {
+            Err(StorageError::CorruptRecord {
+                segment,
+                offset: record_offset,
+            }) => {
+                assert_eq!(segment, offset);
+                assert_eq!(record_offset, data.len() as u32);
+            }
+            other => panic!("expected a corrupt record, got {other:?}"),
+        }
+    }
 }
@@ -1,3 +1,5 @@
+pub mod checksum;
 pub mod commands;
+pub mod config;
 pub mod log;
 pub mod protocol;
@@ -1,4 +1,79 @@
+use crate::internal::log::{
+    CompactionPolicy, Durability, LogConfig, QueueSettings, RetentionPolicy, LOG_CONFIG_SIZE,
+};
+use bytes::{Buf, BytesMut};
 use std::fmt::Display;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+// the largest frame a peer may send, longer frames are rejected before they are buffered
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+// the longest queue name a frame can carry, the v1 and v2 layouts encode its length in a u16
+pub const MAX_QUEUE_NAME_LEN: usize = u16::MAX as usize;
+// [command u32][length u32][payload length u32]
+pub const HEADER_SIZE: usize = 12;
+// the v0 header followed by [queue name length u16]
+pub const V1_HEADER_SIZE: usize = 14;
+// the v0 header followed by [correlation id u32][queue name length u16]
+pub const V2_HEADER_SIZE: usize = 18;
+// set in the command of frames in the v1 and v2 layouts
+pub const FRAME_V1: u32 = 1 << 31;
+pub const FRAME_V2: u32 = 1 << 30;
+// set in the response code of responses that echo a correlation id
+pub const RESPONSE_CORRELATED: u16 = 1 << 15;
+// [response code u16][response message u16][response length u32]
+pub const RESPONSE_HEADER_SIZE: usize = 8;
+
+// why a frame couldn't be decoded
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProtocolError {
+    // the buffer ends before the frame does
+    ShortBuffer { needed: usize, got: usize },
+    // the lengths in the frame contradict each other
+    InconsistentLength,
+    InvalidQueueName,
+    FrameTooLarge { length: usize },
+    // the queue name doesn't fit the u16 length of the v1 and v2 layouts
+    QueueNameTooLong { length: usize },
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::ShortBuffer { needed, got } => {
+                write!(f, "frame needs {needed} bytes, got {got}")
+            }
+            ProtocolError::InconsistentLength => write!(f, "inconsistent frame length"),
+            ProtocolError::InvalidQueueName => write!(f, "queue name is not valid utf-8"),
+            ProtocolError::FrameTooLarge { length } => {
+                write!(f, "frame of {length} bytes exceeds {MAX_FRAME_SIZE} bytes")
+            }
+            ProtocolError::QueueNameTooLong { length } => {
+                write!(
+                    f,
+                    "queue name of {length} bytes exceeds {MAX_QUEUE_NAME_LEN} bytes"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+// checks that data holds at least `needed` bytes
+fn ensure_len(data: &[u8], needed: usize) -> Result<(), ProtocolError> {
+    match data.len() < needed {
+        true => Err(ProtocolError::ShortBuffer {
+            needed,
+            got: data.len(),
+        }),
+        false => Ok(()),
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
 
 #[derive(PartialEq, Debug, Clone)]
 pub struct BinaryHeader {
@@ -7,6 +82,45 @@ pub struct BinaryHeader {
     pub payload_length: u32,
     pub queue_name: Option<String>,
     pub payload: Option<Vec<u8>>,
+    pub format: FrameFormat,
+    // echoed in the response so it can be told apart from the answers to other
+    // requests, only sent in the v2 layout
+    pub correlation_id: u32,
+}
+
+// the layout of a request frame, servers decode both so v0 clients keep working
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum FrameFormat {
+    // [command u32][length u32][payload length u32][queue name][payload], the queue name
+    // is whatever the lengths leave between the header and the payload
+    #[default]
+    V0,
+    // [command u32 | FRAME_V1][length u32][payload length u32][queue name length u16]
+    // [queue name][payload], frames without a queue name have a length of 0
+    V1,
+    // [command u32 | FRAME_V2][length u32][payload length u32][correlation id u32]
+    // [queue name length u16][queue name][payload]
+    V2,
+}
+
+impl FrameFormat {
+    // the layout of a frame from the flags of its command
+    fn of_command(command: u32) -> FrameFormat {
+        if command & FRAME_V2 != 0 {
+            FrameFormat::V2
+        } else if command & FRAME_V1 != 0 {
+            FrameFormat::V1
+        } else {
+            FrameFormat::V0
+        }
+    }
+    fn header_size(&self) -> usize {
+        match self {
+            FrameFormat::V0 => HEADER_SIZE,
+            FrameFormat::V1 => V1_HEADER_SIZE,
+            FrameFormat::V2 => V2_HEADER_SIZE,
+        }
+    }
 }
 #[derive(Debug, PartialEq)]
 pub struct Topic {
@@ -14,29 +128,67 @@ pub struct Topic {
     pub length: u32,
     pub timestamp: u64,
     pub message: Vec<u8>,
+    // optional compaction key, encoded after the message so decoders that only
+    // read up to `length` still work
+    pub key: Option<Vec<u8>>,
 }
 
 impl BinaryHeader {
-    pub fn new(command: u32, queue_name: Option<String>, payload: Option<Vec<u8>>) -> BinaryHeader {
+    /// a v0 frame, queue names longer than MAX_QUEUE_NAME_LEN are refused since their
+    /// length can't be encoded in the other layouts
+    pub fn new(
+        command: u32,
+        queue_name: Option<String>,
+        payload: Option<Vec<u8>>,
+    ) -> Result<BinaryHeader, ProtocolError> {
         let payload_length = payload.clone().map(|s| s.len()).unwrap_or(0) as u32;
-        let queue_name_len = queue_name.clone().map(|s| s.len()).unwrap_or(0) as u32;
+        let queue_name_len = queue_name.as_ref().map(|s| s.len()).unwrap_or(0);
+        if queue_name_len > MAX_QUEUE_NAME_LEN {
+            return Err(ProtocolError::QueueNameTooLong {
+                length: queue_name_len,
+            });
+        }
 
-        BinaryHeader {
+        Ok(BinaryHeader {
             command,
-            length: 12 + payload_length + queue_name_len,
+            length: 12 + payload_length + queue_name_len as u32,
             payload,
             queue_name,
             payload_length,
-        }
+            format: FrameFormat::V0,
+            correlation_id: 0,
+        })
+    }
+    /// the same frame encoded in another layout
+    pub fn with_format(mut self, format: FrameFormat) -> BinaryHeader {
+        let queue_name_len = self.queue_name.as_ref().map(|s| s.len()).unwrap_or(0);
+        self.length = (format.header_size() + queue_name_len) as u32 + self.payload_length;
+        self.format = format;
+        self
+    }
+    /// the frame in the v2 layout carrying a correlation id
+    pub fn with_correlation_id(mut self, correlation_id: u32) -> BinaryHeader {
+        self.correlation_id = correlation_id;
+        self.with_format(FrameFormat::V2)
     }
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut binary: Vec<u8> = Vec::new();
-        let command = self.command.to_be_bytes();
-        let length = self.length.to_be_bytes();
-        let payload_length = self.payload_length.to_be_bytes();
-        binary.append(&mut command.to_vec());
-        binary.append(&mut length.to_vec());
-        binary.append(&mut payload_length.to_vec());
+        let command = match self.format {
+            FrameFormat::V0 => self.command,
+            FrameFormat::V1 => self.command | FRAME_V1,
+            FrameFormat::V2 => self.command | FRAME_V2,
+        };
+        binary.extend(command.to_be_bytes());
+        binary.extend(self.length.to_be_bytes());
+        binary.extend(self.payload_length.to_be_bytes());
+        if self.format == FrameFormat::V2 {
+            binary.extend(self.correlation_id.to_be_bytes());
+        }
+        if self.format != FrameFormat::V0 {
+            // new and from_bytes keep names within MAX_QUEUE_NAME_LEN
+            let queue_name_len = self.queue_name.as_ref().map(|s| s.len()).unwrap_or(0);
+            binary.extend((queue_name_len as u16).to_be_bytes());
+        }
         if let Some(queue_name) = &self.queue_name {
             let data = queue_name.as_bytes();
             binary.append(&mut data.to_vec())
@@ -47,27 +199,865 @@ impl BinaryHeader {
         }
         binary
     }
-    pub fn from_bytes(data: &[u8]) -> BinaryHeader {
-        let command = u32::from_be_bytes(data[..4].try_into().unwrap());
-        let length = u32::from_be_bytes(data[4..8].try_into().unwrap());
-        let payload_length = u32::from_be_bytes(data[8..12].try_into().unwrap());
-        let queue_name_end_pos: usize = (length - payload_length) as usize;
-        let mut _queue_name = None;
-        let mut payload = None;
-        if payload_length > queue_name_end_pos as u32 {
-            _queue_name = Some(String::from_utf8(data[12..queue_name_end_pos].to_vec()).unwrap());
-            payload = Some(data[queue_name_end_pos..].to_vec());
-        } else {
-            _queue_name = Some(String::from_utf8(data[12..queue_name_end_pos].to_vec()).unwrap());
+    /// the length of the frame at the start of data, None until the whole header
+    /// arrived. Lengths that can't be a valid frame are errors since the stream can't
+    /// be split into frames past them
+    pub fn frame_length(data: &[u8]) -> Result<Option<usize>, ProtocolError> {
+        if data.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        let length = read_u32(data, 4) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(ProtocolError::FrameTooLarge { length });
+        }
+        let header_size = FrameFormat::of_command(read_u32(data, 0)).header_size();
+        if length < header_size {
+            return Err(ProtocolError::InconsistentLength);
+        }
+        Ok(Some(length))
+    }
+    pub fn from_bytes(data: &[u8]) -> Result<BinaryHeader, ProtocolError> {
+        ensure_len(data, HEADER_SIZE)?;
+        let format = FrameFormat::of_command(read_u32(data, 0));
+        if format != FrameFormat::V0 {
+            return BinaryHeader::from_bytes_with_name_length(data, format);
+        }
+        let command = read_u32(data, 0);
+        let length = read_u32(data, 4);
+        let payload_length = read_u32(data, 8);
+        if length as usize > MAX_FRAME_SIZE {
+            return Err(ProtocolError::FrameTooLarge {
+                length: length as usize,
+            });
+        }
+        let queue_name_end_pos = (length as usize)
+            .checked_sub(payload_length as usize)
+            .filter(|end| *end >= HEADER_SIZE)
+            .ok_or(ProtocolError::InconsistentLength)?;
+        ensure_len(data, length as usize)?;
+        if queue_name_end_pos - HEADER_SIZE > MAX_QUEUE_NAME_LEN {
+            return Err(ProtocolError::QueueNameTooLong {
+                length: queue_name_end_pos - HEADER_SIZE,
+            });
+        }
+        let queue_name = String::from_utf8(data[HEADER_SIZE..queue_name_end_pos].to_vec())
+            .map_err(|_| ProtocolError::InvalidQueueName)?;
+        let payload =
+            (payload_length > 0).then(|| data[queue_name_end_pos..length as usize].to_vec());
+        Ok(BinaryHeader {
+            command,
+            length,
+            payload_length,
+            queue_name: Some(queue_name),
+            payload,
+            format: FrameFormat::V0,
+            correlation_id: 0,
+        })
+    }
+    // decodes the v1 and v2 layouts, they only differ in the correlation id
+    fn from_bytes_with_name_length(
+        data: &[u8],
+        format: FrameFormat,
+    ) -> Result<BinaryHeader, ProtocolError> {
+        let header_size = format.header_size();
+        ensure_len(data, header_size)?;
+        let command = read_u32(data, 0) & !(FRAME_V1 | FRAME_V2);
+        let length = read_u32(data, 4);
+        let payload_length = read_u32(data, 8);
+        let correlation_id = match format {
+            FrameFormat::V2 => read_u32(data, 12),
+            _ => 0,
+        };
+        let queue_name_len =
+            u16::from_be_bytes([data[header_size - 2], data[header_size - 1]]) as usize;
+        if length as usize > MAX_FRAME_SIZE {
+            return Err(ProtocolError::FrameTooLarge {
+                length: length as usize,
+            });
+        }
+        let queue_name_end_pos = header_size + queue_name_len;
+        if queue_name_end_pos as u64 + payload_length as u64 != length as u64 {
+            return Err(ProtocolError::InconsistentLength);
         }
-        BinaryHeader {
+        ensure_len(data, length as usize)?;
+        let queue_name = match queue_name_len {
+            0 => None,
+            _ => Some(
+                String::from_utf8(data[header_size..queue_name_end_pos].to_vec())
+                    .map_err(|_| ProtocolError::InvalidQueueName)?,
+            ),
+        };
+        let payload =
+            (payload_length > 0).then(|| data[queue_name_end_pos..length as usize].to_vec());
+        Ok(BinaryHeader {
             command,
             length,
             payload_length,
-            queue_name: _queue_name,
+            queue_name,
             payload,
+            format,
+            correlation_id,
+        })
+    }
+}
+
+// options sent as the payload of a SUBSCRIBE request, fields missing from the
+// payload keep their defaults so older clients can send an empty payload
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SubscribeRequest {
+    // the consumer group reading the queue, the default group is used when unset
+    pub group: Option<String>,
+    // how long a message stays in flight waiting for an ACK before it is handed out
+    // again, 0 acknowledges messages as soon as they are read
+    pub visibility_timeout_ms: u32,
+    // how long the server holds the request when there are no new messages, 0 answers
+    // right away
+    pub max_wait_ms: u32,
+    // read up to this many messages in one ResponseWithBatch, only for requests that
+    // acknowledge on read. 0 reads a single message
+    pub max_messages: u32,
+}
+
+impl SubscribeRequest {
+    pub fn new(group: Option<String>) -> SubscribeRequest {
+        SubscribeRequest {
+            group,
+            ..Default::default()
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = encode_group(&self.group);
+        payload.extend(&self.visibility_timeout_ms.to_be_bytes());
+        payload.extend(&self.max_wait_ms.to_be_bytes());
+        payload.extend(&self.max_messages.to_be_bytes());
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> SubscribeRequest {
+        let (group, rest) = decode_group(data);
+        let mut request = SubscribeRequest {
+            group,
+            ..Default::default()
+        };
+        if rest.len() >= 4 {
+            request.visibility_timeout_ms = u32::from_be_bytes(rest[..4].try_into().unwrap());
+        }
+        if rest.len() >= 8 {
+            request.max_wait_ms = u32::from_be_bytes(rest[4..8].try_into().unwrap());
+        }
+        if rest.len() >= 12 {
+            request.max_messages = u32::from_be_bytes(rest[8..12].try_into().unwrap());
+        }
+        request
+    }
+}
+
+// the payload of a STREAM request, the server pushes up to `credits` messages and
+// the client grants more with CREDIT requests as it processes them
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct StreamRequest {
+    pub group: Option<String>,
+    pub visibility_timeout_ms: u32,
+    pub credits: u32,
+}
+
+impl StreamRequest {
+    pub fn new(group: Option<String>, visibility_timeout_ms: u32, credits: u32) -> StreamRequest {
+        StreamRequest {
+            group,
+            visibility_timeout_ms,
+            credits,
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = encode_group(&self.group);
+        payload.extend(&self.visibility_timeout_ms.to_be_bytes());
+        payload.extend(&self.credits.to_be_bytes());
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<StreamRequest> {
+        let (group, rest) = decode_group(data);
+        if rest.len() < 8 {
+            return None;
+        }
+        Some(StreamRequest {
+            group,
+            visibility_timeout_ms: u32::from_be_bytes(rest[..4].try_into().unwrap()),
+            credits: u32::from_be_bytes(rest[4..8].try_into().unwrap()),
+        })
+    }
+}
+
+// the payload of ACK and NACK requests
+#[derive(Debug, PartialEq, Clone)]
+pub struct AckRequest {
+    pub group: Option<String>,
+    // the delivery id the message was handed out with
+    pub id: u64,
+}
+
+impl AckRequest {
+    pub fn new(group: Option<String>, id: u64) -> AckRequest {
+        AckRequest { group, id }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = encode_group(&self.group);
+        payload.extend(&self.id.to_be_bytes());
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<AckRequest> {
+        let (group, rest) = decode_group(data);
+        if rest.len() < 8 {
+            return None;
+        }
+        let id = u64::from_be_bytes(rest[..8].try_into().unwrap());
+        Some(AckRequest { group, id })
+    }
+}
+
+// the body of a ResponseWithDelivery, a message that has to be acknowledged
+#[derive(Debug, PartialEq, Clone)]
+pub struct Delivery {
+    pub id: u64,
+    pub message: Vec<u8>,
+}
+
+impl Delivery {
+    pub fn new(id: u64, message: Vec<u8>) -> Delivery {
+        Delivery { id, message }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::with_capacity(8 + self.message.len());
+        payload.extend(&self.id.to_be_bytes());
+        payload.extend(&self.message);
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<Delivery> {
+        if data.len() < 8 {
+            return None;
+        }
+        Some(Delivery {
+            id: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            message: data[8..].to_vec(),
+        })
+    }
+}
+
+// a message moved to the <queue>.dlq queue after it was handed out too many times
+// without being acknowledged
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeadLetterMessage {
+    // the queue the message was published to
+    pub queue: String,
+    // the consumer group that failed to process it
+    pub group: String,
+    // the delivery id of the message in the original queue
+    pub offset: u64,
+    pub deliveries: u32,
+    pub message: Vec<u8>,
+}
+
+impl DeadLetterMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = encode_group(&Some(self.queue.clone()));
+        payload.extend(encode_group(&Some(self.group.clone())));
+        payload.extend(&self.offset.to_be_bytes());
+        payload.extend(&self.deliveries.to_be_bytes());
+        payload.extend(&self.message);
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<DeadLetterMessage> {
+        let (queue, rest) = decode_group(data);
+        let (group, rest) = decode_group(rest);
+        if rest.len() < 12 {
+            return None;
+        }
+        Some(DeadLetterMessage {
+            queue: queue?,
+            group: group?,
+            offset: u64::from_be_bytes(rest[..8].try_into().unwrap()),
+            deliveries: u32::from_be_bytes(rest[8..12].try_into().unwrap()),
+            message: rest[12..].to_vec(),
+        })
+    }
+}
+
+// reads messages by offset without moving any consumer group
+#[derive(Debug, PartialEq, Clone)]
+pub struct FetchRequest {
+    pub offset: u64,
+    pub max_messages: u32,
+    pub max_bytes: u32,
+    // how long the server holds the request when there are no messages at the offset
+    // yet, optional on the wire
+    pub max_wait_ms: u32,
+}
+
+impl FetchRequest {
+    pub fn new(offset: u64, max_messages: u32, max_bytes: u32) -> FetchRequest {
+        FetchRequest {
+            offset,
+            max_messages,
+            max_bytes,
+            max_wait_ms: 0,
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::with_capacity(20);
+        payload.extend(&self.offset.to_be_bytes());
+        payload.extend(&self.max_messages.to_be_bytes());
+        payload.extend(&self.max_bytes.to_be_bytes());
+        payload.extend(&self.max_wait_ms.to_be_bytes());
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<FetchRequest> {
+        if data.len() < 16 {
+            return None;
+        }
+        Some(FetchRequest {
+            offset: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            max_messages: u32::from_be_bytes(data[8..12].try_into().unwrap()),
+            max_bytes: u32::from_be_bytes(data[12..16].try_into().unwrap()),
+            max_wait_ms: data
+                .get(16..20)
+                .map(|wait| u32::from_be_bytes(wait.try_into().unwrap()))
+                .unwrap_or(0),
+        })
+    }
+}
+
+// a stored message together with its offset in the queue
+#[derive(Debug, PartialEq, Clone)]
+pub struct Record {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+// the body of a ResponseWithBatch, encoded as a count followed by
+// [offset u64][length u32][data] for every record
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RecordBatch {
+    pub records: Vec<Record>,
+}
+
+impl RecordBatch {
+    pub fn new(records: Vec<Record>) -> RecordBatch {
+        RecordBatch { records }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::new();
+        payload.extend(&(self.records.len() as u32).to_be_bytes());
+        for record in &self.records {
+            payload.extend(&record.offset.to_be_bytes());
+            payload.extend(&(record.data.len() as u32).to_be_bytes());
+            payload.extend(&record.data);
+        }
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<RecordBatch> {
+        if data.len() < 4 {
+            return None;
+        }
+        let count = u32::from_be_bytes(data[..4].try_into().unwrap());
+        let mut records = Vec::new();
+        let mut rest = &data[4..];
+        for _ in 0..count {
+            if rest.len() < 12 {
+                return None;
+            }
+            let offset = u64::from_be_bytes(rest[..8].try_into().unwrap());
+            let len = u32::from_be_bytes(rest[8..12].try_into().unwrap()) as usize;
+            if rest.len() < 12 + len {
+                return None;
+            }
+            records.push(Record {
+                offset,
+                data: rest[12..12 + len].to_vec(),
+            });
+            rest = &rest[12 + len..];
+        }
+        Some(RecordBatch { records })
+    }
+}
+
+// the payload of PUBLISH_BATCH, topics appended to the queue as one write. Encoded as
+// a count followed by [length u32][topic] for every topic
+#[derive(Debug, PartialEq, Default)]
+pub struct PublishBatch {
+    pub topics: Vec<Topic>,
+}
+
+impl PublishBatch {
+    pub fn new(topics: Vec<Topic>) -> PublishBatch {
+        PublishBatch { topics }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::new();
+        payload.extend(&(self.topics.len() as u32).to_be_bytes());
+        for topic in &self.topics {
+            let topic = topic.to_bytes();
+            payload.extend(&(topic.len() as u32).to_be_bytes());
+            payload.extend(topic);
+        }
+        payload
+    }
+    // the encoded topics of the batch as they are stored
+    pub fn records(data: &[u8]) -> Option<Vec<&[u8]>> {
+        if data.len() < 4 {
+            return None;
+        }
+        let count = u32::from_be_bytes(data[..4].try_into().unwrap());
+        let mut records = Vec::new();
+        let mut rest = &data[4..];
+        for _ in 0..count {
+            if rest.len() < 4 {
+                return None;
+            }
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            if rest.len() < 4 + len || len < 16 {
+                return None;
+            }
+            records.push(&rest[4..4 + len]);
+            rest = &rest[4 + len..];
+        }
+        Some(records)
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<PublishBatch> {
+        let topics = PublishBatch::records(data)?
+            .into_iter()
+            .map(Topic::from_bytes)
+            .collect::<Result<_, _>>()
+            .ok()?;
+        Some(PublishBatch { topics })
+    }
+}
+
+// the body of a ResponseWithOffsets, the offsets assigned to a published batch
+pub fn encode_offsets(offsets: &[u64]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::with_capacity(4 + offsets.len() * 8);
+    payload.extend(&(offsets.len() as u32).to_be_bytes());
+    for offset in offsets {
+        payload.extend(&offset.to_be_bytes());
+    }
+    payload
+}
+
+pub fn decode_offsets(data: &[u8]) -> Option<Vec<u64>> {
+    let count = u32::from_be_bytes(data.get(..4)?.try_into().unwrap()) as usize;
+    let offsets = data[4..].chunks_exact(8);
+    if offsets.len() != count {
+        return None;
+    }
+    Some(
+        offsets
+            .map(|offset| u64::from_be_bytes(offset.try_into().unwrap()))
+            .collect(),
+    )
+}
+
+// the protocol version spoken by this build. Compatibility policy: a server speaks every
+// version from MIN_PROTOCOL_VERSION up to PROTOCOL_VERSION and answers a newer client
+// with its own version, which the client has to speak or disconnect. Within a version
+// fields are only ever appended to payloads and decoders ignore trailing bytes they
+// don't know, so new optional fields don't need a new version
+pub const PROTOCOL_VERSION: u16 = 3;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+// starts every HELLO payload so other protocols are told apart from old versions
+pub const HELLO_MAGIC: [u8; 2] = *b"MQ";
+
+// optional features negotiated by HELLO, a feature is only used once both sides set it
+pub const FEATURE_COMPRESSION: u32 = 1 << 0;
+pub const FEATURE_BATCHING: u32 = 1 << 1;
+pub const FEATURE_HEADERS: u32 = 1 << 2;
+// the features this build implements
+pub const SUPPORTED_FEATURES: u32 = FEATURE_BATCHING;
+
+// the payload of a HELLO request and of its ResponseWithHello, encoded as
+// [magic 2 bytes][version u16][features u32]. Clients send the highest version they
+// speak and the features they want, the server answers with what was agreed on
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Hello {
+    pub version: u16,
+    pub features: u32,
+}
+
+impl Hello {
+    pub fn new(version: u16, features: u32) -> Hello {
+        Hello { version, features }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::with_capacity(8);
+        payload.extend(&HELLO_MAGIC);
+        payload.extend(&self.version.to_be_bytes());
+        payload.extend(&self.features.to_be_bytes());
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<Hello> {
+        if data.len() < 8 || data[..2] != HELLO_MAGIC {
+            return None;
+        }
+        Some(Hello {
+            version: u16::from_be_bytes([data[2], data[3]]),
+            features: read_u32(data, 4),
+        })
+    }
+    /// the version and features the server agrees on for a client's HELLO, None when
+    /// the client only speaks versions older than MIN_PROTOCOL_VERSION
+    pub fn negotiate(&self) -> Option<Hello> {
+        if self.version < MIN_PROTOCOL_VERSION {
+            return None;
+        }
+        Some(Hello {
+            version: self.version.min(PROTOCOL_VERSION),
+            features: self.features & SUPPORTED_FEATURES,
+        })
+    }
+    pub fn supports(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+    /// the layout of request frames, version 2 added the v1 layout and version 3 the
+    /// v2 layout with correlation ids
+    pub fn frame_format(&self) -> FrameFormat {
+        match self.version {
+            0 | 1 => FrameFormat::V0,
+            2 => FrameFormat::V1,
+            _ => FrameFormat::V2,
+        }
+    }
+}
+
+// the body of a ResponseWithPong, encoded as [server time u64][protocol version u16]
+// [server version length u16][server version]. The time is in milliseconds since the
+// unix epoch
+#[derive(Debug, PartialEq, Clone)]
+pub struct Pong {
+    pub server_time_ms: u64,
+    pub protocol_version: u16,
+    pub server_version: String,
+}
+
+impl Pong {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::with_capacity(12 + self.server_version.len());
+        payload.extend(&self.server_time_ms.to_be_bytes());
+        payload.extend(&self.protocol_version.to_be_bytes());
+        payload.extend(encode_name(&self.server_version));
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<Pong> {
+        if data.len() < 10 {
+            return None;
+        }
+        let (server_version, _) = decode_name(&data[10..])?;
+        Some(Pong {
+            server_time_ms: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            protocol_version: u16::from_be_bytes([data[8], data[9]]),
+            server_version,
+        })
+    }
+}
+
+// how far a consumer group is behind the end of the queue
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConsumerStats {
+    pub group: String,
+    // messages the group has not read yet
+    pub lag: u64,
+    // messages handed out to the group and not acknowledged yet
+    pub in_flight: u32,
+}
+
+// the storage of a queue as reported by STATS
+#[derive(Debug, PartialEq, Clone)]
+pub struct QueueStats {
+    pub name: String,
+    pub segments: u32,
+    // bytes of record data held by the queue's segments
+    pub bytes: u64,
+    pub messages: u64,
+    // offset of the oldest message still stored
+    pub start_offset: u64,
+    // the write position, the offset the next message appended gets
+    pub next_offset: u64,
+    pub consumers: Vec<ConsumerStats>,
+}
+
+// the body of a ResponseWithStats, encoded as a count followed by
+// [name][segments u32][bytes u64][messages u64][start offset u64][next offset u64]
+// [consumer count u32] for every queue, each consumer then being [group][lag u64]
+// [in flight u32]. Names are a u16 length followed by the name
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Stats {
+    pub queues: Vec<QueueStats>,
+}
+
+impl QueueStats {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = encode_name(&self.name);
+        payload.extend(&self.segments.to_be_bytes());
+        payload.extend(&self.bytes.to_be_bytes());
+        payload.extend(&self.messages.to_be_bytes());
+        payload.extend(&self.start_offset.to_be_bytes());
+        payload.extend(&self.next_offset.to_be_bytes());
+        payload.extend(&(self.consumers.len() as u32).to_be_bytes());
+        for consumer in &self.consumers {
+            payload.extend(encode_name(&consumer.group));
+            payload.extend(&consumer.lag.to_be_bytes());
+            payload.extend(&consumer.in_flight.to_be_bytes());
+        }
+        payload
+    }
+    // decodes the stats of a queue and returns the rest of the payload
+    fn decode(data: &[u8]) -> Option<(QueueStats, &[u8])> {
+        let (name, tail) = decode_name(data)?;
+        if tail.len() < 40 {
+            return None;
+        }
+        let read_u64 = |at: usize| u64::from_be_bytes(tail[at..at + 8].try_into().unwrap());
+        let mut queue = QueueStats {
+            name,
+            segments: read_u32(tail, 0),
+            bytes: read_u64(4),
+            messages: read_u64(12),
+            start_offset: read_u64(20),
+            next_offset: read_u64(28),
+            consumers: Vec::new(),
+        };
+        let consumers = read_u32(tail, 36);
+        let mut rest = &tail[40..];
+        for _ in 0..consumers {
+            let (group, tail) = decode_name(rest)?;
+            if tail.len() < 12 {
+                return None;
+            }
+            queue.consumers.push(ConsumerStats {
+                group,
+                lag: u64::from_be_bytes(tail[..8].try_into().unwrap()),
+                in_flight: read_u32(tail, 8),
+            });
+            rest = &tail[12..];
+        }
+        Some((queue, rest))
+    }
+}
+
+impl Stats {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::new();
+        payload.extend(&(self.queues.len() as u32).to_be_bytes());
+        for queue in &self.queues {
+            payload.extend(queue.to_bytes());
+        }
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<Stats> {
+        if data.len() < 4 {
+            return None;
+        }
+        let count = read_u32(data, 0);
+        let mut queues = Vec::new();
+        let mut rest = &data[4..];
+        for _ in 0..count {
+            let (queue, tail) = QueueStats::decode(rest)?;
+            queues.push(queue);
+            rest = tail;
+        }
+        Some(Stats { queues })
+    }
+}
+
+// the body of a ResponseWithDescription, encoded as [QueueSettings][QueueStats]
+#[derive(Debug, PartialEq, Clone)]
+pub struct QueueDescription {
+    pub settings: QueueSettings,
+    pub stats: QueueStats,
+}
+
+impl QueueDescription {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = self.settings.to_bytes();
+        payload.extend(self.stats.to_bytes());
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<QueueDescription> {
+        let settings = QueueSettings::from_bytes(data)?;
+        let (stats, _) = QueueStats::decode(&data[QUEUE_SETTINGS_SIZE..])?;
+        Some(QueueDescription { settings, stats })
+    }
+}
+
+// bytes of an encoded QueueSettings
+const QUEUE_SETTINGS_SIZE: usize = 9 + LOG_CONFIG_SIZE;
+
+// the body of a ResponseWithQueues, a count followed by the names
+pub fn encode_queue_names(names: &[String]) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::new();
+    payload.extend(&(names.len() as u32).to_be_bytes());
+    for name in names {
+        payload.extend(encode_name(name));
+    }
+    payload
+}
+
+pub fn decode_queue_names(data: &[u8]) -> Option<Vec<String>> {
+    let count = u32::from_be_bytes(data.get(..4)?.try_into().unwrap());
+    let mut names = Vec::new();
+    let mut rest = &data[4..];
+    for _ in 0..count {
+        let (name, tail) = decode_name(rest)?;
+        names.push(name);
+        rest = tail;
+    }
+    Some(names)
+}
+
+// names in response bodies are encoded as a u16 length followed by the name
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::with_capacity(2 + name.len());
+    payload.extend(&(name.len() as u16).to_be_bytes());
+    payload.extend(name.as_bytes());
+    payload
+}
+
+// decodes a name and returns the rest of the payload
+fn decode_name(data: &[u8]) -> Option<(String, &[u8])> {
+    if data.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+    let name = data.get(2..2 + len)?;
+    let name = String::from_utf8(name.to_vec()).ok()?;
+    Some((name, &data[2 + len..]))
+}
+
+// the settings changed by an ALTER_QUEUE request
+pub const SETTING_SEGMENT_SIZE: u32 = 1;
+pub const SETTING_INDEX_SIZE: u32 = 1 << 1;
+pub const SETTING_RETENTION: u32 = 1 << 2;
+pub const SETTING_DURABILITY: u32 = 1 << 3;
+pub const SETTING_MAX_MESSAGE_SIZE: u32 = 1 << 4;
+pub const SETTING_COMPACTION: u32 = 1 << 5;
+pub const SETTING_MAX_DELIVERIES: u32 = 1 << 6;
+
+// the payload of ALTER_QUEUE, encoded as [fields u32][LogConfig]. Only the settings
+// whose SETTING_ bit is set in fields are changed, the others are left as they are
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct AlterQueueRequest {
+    pub fields: u32,
+    pub config: LogConfig,
+}
+
+impl AlterQueueRequest {
+    pub fn new() -> AlterQueueRequest {
+        AlterQueueRequest::default()
+    }
+    pub fn with_segment_size(mut self, segment_size: u64) -> AlterQueueRequest {
+        self.fields |= SETTING_SEGMENT_SIZE;
+        self.config.segment_size = segment_size;
+        self
+    }
+    pub fn with_index_size(mut self, index_size: u64) -> AlterQueueRequest {
+        self.fields |= SETTING_INDEX_SIZE;
+        self.config.index_size = index_size;
+        self
+    }
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> AlterQueueRequest {
+        self.fields |= SETTING_RETENTION;
+        self.config.retention = retention;
+        self
+    }
+    pub fn with_durability(mut self, durability: Durability) -> AlterQueueRequest {
+        self.fields |= SETTING_DURABILITY;
+        self.config.durability = durability;
+        self
+    }
+    pub fn with_max_message_size(mut self, max_message_size: Option<u32>) -> AlterQueueRequest {
+        self.fields |= SETTING_MAX_MESSAGE_SIZE;
+        self.config.max_message_size = max_message_size;
+        self
+    }
+    pub fn with_compaction(mut self, compaction: Option<CompactionPolicy>) -> AlterQueueRequest {
+        self.fields |= SETTING_COMPACTION;
+        self.config.compaction = compaction;
+        self
+    }
+    pub fn with_max_deliveries(mut self, max_deliveries: Option<u32>) -> AlterQueueRequest {
+        self.fields |= SETTING_MAX_DELIVERIES;
+        self.config.max_deliveries = max_deliveries;
+        self
+    }
+    /// the settings of a queue after the request changed them
+    pub fn apply(&self, config: &LogConfig) -> LogConfig {
+        let changed = |field: u32| self.fields & field == field;
+        LogConfig {
+            segment_size: match changed(SETTING_SEGMENT_SIZE) {
+                true => self.config.segment_size,
+                false => config.segment_size,
+            },
+            index_size: match changed(SETTING_INDEX_SIZE) {
+                true => self.config.index_size,
+                false => config.index_size,
+            },
+            retention: match changed(SETTING_RETENTION) {
+                true => self.config.retention,
+                false => config.retention,
+            },
+            durability: match changed(SETTING_DURABILITY) {
+                true => self.config.durability,
+                false => config.durability,
+            },
+            max_message_size: match changed(SETTING_MAX_MESSAGE_SIZE) {
+                true => self.config.max_message_size,
+                false => config.max_message_size,
+            },
+            compaction: match changed(SETTING_COMPACTION) {
+                true => self.config.compaction,
+                false => config.compaction,
+            },
+            max_deliveries: match changed(SETTING_MAX_DELIVERIES) {
+                true => self.config.max_deliveries,
+                false => config.max_deliveries,
+            },
         }
     }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = self.fields.to_be_bytes().to_vec();
+        payload.extend(self.config.to_bytes());
+        payload
+    }
+    pub fn from_bytes(data: &[u8]) -> Option<AlterQueueRequest> {
+        if data.len() < 4 {
+            return None;
+        }
+        Some(AlterQueueRequest {
+            fields: read_u32(data, 0),
+            config: LogConfig::from_bytes(&data[4..])?,
+        })
+    }
+}
+
+// consumer groups are encoded as a length followed by the name, 0 is the default group
+fn encode_group(group: &Option<String>) -> Vec<u8> {
+    let group = group.clone().unwrap_or_default();
+    let mut payload: Vec<u8> = Vec::with_capacity(4 + group.len());
+    payload.extend(&(group.len() as u32).to_be_bytes());
+    payload.extend(group.as_bytes());
+    payload
+}
+
+// decodes a consumer group and returns the rest of the payload
+fn decode_group(data: &[u8]) -> (Option<String>, &[u8]) {
+    if data.len() < 4 {
+        return (None, &[]);
+    }
+    let group_len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    if data.len() < 4 + group_len {
+        return (None, &[]);
+    }
+    let group = match group_len {
+        0 => None,
+        _ => String::from_utf8(data[4..4 + group_len].to_vec()).ok(),
+    };
+    (group, &data[4 + group_len..])
 }
 
 impl Topic {
@@ -77,8 +1067,20 @@ impl Topic {
             length: 16 + message.len() as u32,
             timestamp,
             message,
+            key: None,
         }
     }
+    /// a keyed message, only the latest message of a key survives compaction and an
+    /// empty message is a tombstone that deletes the key
+    pub fn with_key(id: u32, timestamp: u64, key: Vec<u8>, message: Vec<u8>) -> Topic {
+        Topic {
+            key: Some(key),
+            ..Topic::new(id, timestamp, message)
+        }
+    }
+    pub fn is_tombstone(&self) -> bool {
+        self.key.is_some() && self.message.is_empty()
+    }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut payload: Vec<u8> = Vec::with_capacity(
@@ -92,21 +1094,39 @@ impl Topic {
         payload.extend(&self.length.to_be_bytes());
         payload.extend(&self.timestamp.to_be_bytes());
         payload.extend(&self.message);
+        if let Some(key) = &self.key {
+            payload.extend(&(key.len() as u32).to_be_bytes());
+            payload.extend(key);
+        }
 
         payload
     }
 
-    pub fn from_bytes(data: &[u8]) -> Topic {
-        let id = u32::from_be_bytes(data[..4].try_into().unwrap());
-        let length = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    pub fn from_bytes(data: &[u8]) -> Result<Topic, ProtocolError> {
+        ensure_len(data, 16)?;
+        let id = read_u32(data, 0);
+        let length = read_u32(data, 4);
         let timestamp = u64::from_be_bytes(data[8..16].try_into().unwrap());
+        if length < 16 {
+            return Err(ProtocolError::InconsistentLength);
+        }
+        ensure_len(data, length as usize)?;
         let message: Vec<u8> = data[16..length as usize].to_vec();
-        Topic {
+        let mut key = None;
+        let key_start = length as usize + 4;
+        if data.len() >= key_start {
+            let key_len = read_u32(data, length as usize) as usize;
+            if data.len() - key_start >= key_len {
+                key = Some(data[key_start..key_start + key_len].to_vec());
+            }
+        }
+        Ok(Topic {
             id,
             length,
             timestamp,
             message,
-        }
+            key,
+        })
     }
 }
 
@@ -133,6 +1153,36 @@ pub enum ResponseMessage {
 
     // error specifying queue_name is required
     MessageBodyRequired = 6,
+    // error specifying the stored record failed its checksum
+    CorruptRecord = 7,
+    // responseheader with a Delivery body that has to be acknowledged
+    ResponseWithDelivery = 8,
+    // responseheader with a RecordBatch body
+    ResponseWithBatch = 9,
+    // responseheader with the offsets assigned to a published batch
+    ResponseWithOffsets = 10,
+    // error with a string body saying why the request frame couldn't be decoded
+    InvalidFrame = 11,
+    // responseheader with the Hello the server agreed on
+    ResponseWithHello = 12,
+    // error with a [min u16][max u16] body, the versions the server speaks
+    UnsupportedVersion = 13,
+    // error sent for requests on a connection that didn't start with HELLO
+    HandshakeRequired = 14,
+    // responseheader with a Pong body
+    ResponseWithPong = 15,
+    // responseheader with a Stats body
+    ResponseWithStats = 16,
+    // the last response on a connection, sent before the server closes it after QUIT
+    Goodbye = 17,
+    // responseheader with the QueueSettings of a queue
+    ResponseWithSettings = 18,
+    // error sent for requests naming a queue that doesn't exist and won't be created
+    QueueNotFound = 19,
+    // responseheader with the names of the queues, see encode_queue_names
+    ResponseWithQueues = 20,
+    // responseheader with a QueueDescription body
+    ResponseWithDescription = 21,
     UNKNOWN,
 }
 
@@ -148,6 +1198,10 @@ pub struct Response {
     pub response_message: u16,
     pub response_length: u32,
     pub response_data: Option<Vec<u8>>,
+    // the correlation id of the request this answers, encoded after the header with
+    // RESPONSE_CORRELATED set in the response code. Responses to requests in the v0 and
+    // v1 layouts have none
+    pub correlation_id: Option<u32>,
 }
 
 impl Response {
@@ -163,38 +1217,176 @@ impl Response {
             response_message: response_message as u16,
             response_length: 8 + len_data as u32,
             response_data,
+            correlation_id: None,
+        }
+    }
+    /// the response echoing the correlation id of the request it answers
+    pub fn with_correlation_id(mut self, correlation_id: u32) -> Self {
+        if self.correlation_id.is_none() {
+            self.response_length += 4;
         }
+        self.correlation_id = Some(correlation_id);
+        self
     }
     pub fn to_bytes(&self) -> Vec<u8> {
-        let len_data = self.response_data.clone().map(|s| s.len());
-        let mut payload: Vec<u8> = Vec::with_capacity(
-            self.response_code.to_be_bytes().len()
-                + self.response_message.to_be_bytes().len()
-                + self.response_length.to_be_bytes().len()
-                + len_data.unwrap_or(0),
-        );
-        payload.extend(&self.response_code.to_be_bytes());
+        let mut payload: Vec<u8> = Vec::with_capacity(self.response_length as usize);
+        let response_code = match self.correlation_id {
+            Some(_) => self.response_code | RESPONSE_CORRELATED,
+            None => self.response_code,
+        };
+        payload.extend(&response_code.to_be_bytes());
         payload.extend(&self.response_message.to_be_bytes());
         payload.extend(&self.response_length.to_be_bytes());
+        if let Some(correlation_id) = self.correlation_id {
+            payload.extend(&correlation_id.to_be_bytes());
+        }
         if let Some(data) = &self.response_data {
             payload.extend(data);
         }
         payload
     }
-    pub fn from_bytes(data: &[u8]) -> Self {
-        let mut response_data = None;
-        let response_code = u16::from_be_bytes(data[..2].try_into().unwrap());
-        let response_message = u16::from_be_bytes(data[2..4].try_into().unwrap());
-        let response_length = u32::from_be_bytes(data[4..8].try_into().unwrap());
-        if response_length > 8 {
-            response_data = Some(data[8..response_length as usize].to_vec());
+    /// the length of the response at the start of data, None until the whole header
+    /// arrived
+    pub fn frame_length(data: &[u8]) -> Result<Option<usize>, ProtocolError> {
+        if data.len() < RESPONSE_HEADER_SIZE {
+            return Ok(None);
         }
-        Self {
-            response_code,
+        let length = read_u32(data, 4) as usize;
+        if length > MAX_FRAME_SIZE {
+            return Err(ProtocolError::FrameTooLarge { length });
+        }
+        if length < RESPONSE_HEADER_SIZE {
+            return Err(ProtocolError::InconsistentLength);
+        }
+        Ok(Some(length))
+    }
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtocolError> {
+        let response_length = Response::frame_length(data)?.ok_or(ProtocolError::ShortBuffer {
+            needed: RESPONSE_HEADER_SIZE,
+            got: data.len(),
+        })?;
+        ensure_len(data, response_length)?;
+        let response_code = u16::from_be_bytes([data[0], data[1]]);
+        let response_message = u16::from_be_bytes([data[2], data[3]]);
+        let (correlation_id, header_size) = match response_code & RESPONSE_CORRELATED {
+            0 => (None, RESPONSE_HEADER_SIZE),
+            _ if response_length < RESPONSE_HEADER_SIZE + 4 => {
+                return Err(ProtocolError::InconsistentLength)
+            }
+            _ => (Some(read_u32(data, 8)), RESPONSE_HEADER_SIZE + 4),
+        };
+        let response_data =
+            (response_length > header_size).then(|| data[header_size..response_length].to_vec());
+        Ok(Self {
+            response_code: response_code & !RESPONSE_CORRELATED,
             response_message,
-            response_length,
+            response_length: response_length as u32,
             response_data,
+            correlation_id,
+        })
+    }
+}
+
+// errors of the framed codecs, a protocol error means the stream can't be split into
+// frames anymore
+#[derive(Debug)]
+pub enum CodecError {
+    Protocol(ProtocolError),
+    Io(io::Error),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Protocol(e) => write!(f, "{e}"),
+            CodecError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<io::Error> for CodecError {
+    fn from(error: io::Error) -> Self {
+        CodecError::Io(error)
+    }
+}
+
+impl From<ProtocolError> for CodecError {
+    fn from(error: ProtocolError) -> Self {
+        CodecError::Protocol(error)
+    }
+}
+
+impl From<CodecError> for io::Error {
+    fn from(error: CodecError) -> Self {
+        match error {
+            CodecError::Io(e) => e,
+            CodecError::Protocol(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// the server side of the wire protocol, decodes requests and encodes responses.
+/// A frame that can't be decoded is skipped and yielded as an error so the connection
+/// can answer it and go on with the next frame
+#[derive(Debug, Default)]
+pub struct ServerCodec;
+
+impl Decoder for ServerCodec {
+    type Item = Result<BinaryHeader, ProtocolError>;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(length) = BinaryHeader::frame_length(src)? else {
+            return Ok(None);
+        };
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(length);
+        Ok(Some(BinaryHeader::from_bytes(&frame)))
+    }
+}
+
+impl Encoder<Response> for ServerCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, resp: Response, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&resp.to_bytes());
+        Ok(())
+    }
+}
+
+/// the client side of the wire protocol, encodes requests and decodes responses
+#[derive(Debug, Default)]
+pub struct ClientCodec;
+
+impl Decoder for ClientCodec {
+    type Item = Response;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(length) = Response::frame_length(src)? else {
+            return Ok(None);
+        };
+        if src.len() < length {
+            src.reserve(length - src.len());
+            return Ok(None);
         }
+        let resp = Response::from_bytes(&src[..length])?;
+        src.advance(length);
+        Ok(Some(resp))
+    }
+}
+
+impl Encoder<BinaryHeader> for ClientCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, header: BinaryHeader, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&header.to_bytes());
+        Ok(())
     }
 }
 
@@ -211,28 +1403,296 @@ impl ResponseMessage {
     }
 }
 
+#[cfg(test)]
 mod test {
     #![allow(unused_imports)]
     use crate::internal::protocol::*;
+    use proptest::prelude::*;
+    use std::time::Duration;
     #[test]
     fn tcp_header_byte_test() {
         let message = BinaryHeader::new(
             1,
             Some("new".to_string()),
             Some(b"random byte data".to_vec()),
+        )
+        .unwrap();
+        assert_eq!(
+            Ok(message.clone()),
+            BinaryHeader::from_bytes(&message.to_bytes())
         );
-        assert_eq!(message, BinaryHeader::from_bytes(&message.to_bytes()));
     }
     #[test]
     fn tcp_header_byte_test_without_data() {
-        let message = BinaryHeader::new(1, Some("new".to_string()), None);
-        assert_eq!(message, BinaryHeader::from_bytes(&message.to_bytes()));
+        let message = BinaryHeader::new(1, Some("new".to_string()), None).unwrap();
+        assert_eq!(
+            Ok(message.clone()),
+            BinaryHeader::from_bytes(&message.to_bytes())
+        );
+    }
+    #[test]
+    fn tcp_header_byte_test_with_short_data() {
+        let message =
+            BinaryHeader::new(1, Some("a-long-queue-name".to_string()), Some(vec![1])).unwrap();
+        assert_eq!(
+            Ok(message.clone()),
+            BinaryHeader::from_bytes(&message.to_bytes())
+        );
+    }
+    #[test]
+    fn tcp_header_rejects_long_queue_names() {
+        let name = "q".repeat(MAX_QUEUE_NAME_LEN);
+        let message = BinaryHeader::new(2, Some(name.clone()), None).unwrap();
+        assert_eq!(
+            Ok(message.clone().with_format(FrameFormat::V1)),
+            BinaryHeader::from_bytes(&message.with_format(FrameFormat::V1).to_bytes())
+        );
+        let name = name + "q";
+        assert_eq!(
+            Err(ProtocolError::QueueNameTooLong {
+                length: MAX_QUEUE_NAME_LEN + 1
+            }),
+            BinaryHeader::new(2, Some(name.clone()), None)
+        );
+        // a v0 frame can carry the name but it couldn't be encoded in the other layouts
+        let mut frame = 2u32.to_be_bytes().to_vec();
+        frame.extend(((HEADER_SIZE + name.len()) as u32).to_be_bytes());
+        frame.extend(0u32.to_be_bytes());
+        frame.extend(name.as_bytes());
+        assert!(matches!(
+            BinaryHeader::from_bytes(&frame),
+            Err(ProtocolError::QueueNameTooLong { .. })
+        ));
+    }
+    #[test]
+    fn tcp_header_v1_byte_test() {
+        let message = BinaryHeader::new(1, Some("a-long-queue-name".to_string()), Some(vec![1]))
+            .unwrap()
+            .with_format(FrameFormat::V1);
+        assert_eq!(message.length as usize, message.to_bytes().len());
+        assert_eq!(
+            Ok(message.clone()),
+            BinaryHeader::from_bytes(&message.to_bytes())
+        );
+        // frames without a queue name, e.g. PING
+        let message = BinaryHeader::new(3, None, None)
+            .unwrap()
+            .with_format(FrameFormat::V1);
+        assert_eq!(message.to_bytes().len(), V1_HEADER_SIZE);
+        assert_eq!(
+            Ok(message.clone()),
+            BinaryHeader::from_bytes(&message.to_bytes())
+        );
+        // the v0 layout can't tell a missing queue name from an empty one
+        let message = BinaryHeader::new(3, None, None).unwrap();
+        let decoded = BinaryHeader::from_bytes(&message.to_bytes()).unwrap();
+        assert_eq!(decoded.queue_name, Some(String::new()));
+    }
+    #[test]
+    fn tcp_header_v2_byte_test() {
+        let message = BinaryHeader::new(8, Some("orders".to_string()), Some(vec![1, 2]))
+            .unwrap()
+            .with_correlation_id(42);
+        assert_eq!(message.format, FrameFormat::V2);
+        assert_eq!(message.length as usize, message.to_bytes().len());
+        assert_eq!(
+            Ok(message.clone()),
+            BinaryHeader::from_bytes(&message.to_bytes())
+        );
+        let message = BinaryHeader::new(3, None, None)
+            .unwrap()
+            .with_correlation_id(u32::MAX);
+        assert_eq!(
+            Ok(message.clone()),
+            BinaryHeader::from_bytes(&message.to_bytes())
+        );
+    }
+    #[test]
+    fn response_correlation_byte_test() {
+        let message = Response::new(
+            ResponseCode::Err,
+            ResponseMessage::ErrorResponse,
+            Some(b"no such queue".to_vec()),
+        )
+        .with_correlation_id(7);
+        let bytes = message.to_bytes();
+        assert_eq!(message.response_length as usize, bytes.len());
+        assert_eq!(Response::from_bytes(&bytes), Ok(message));
+        // older clients read the code without the correlation flag only from
+        // responses to v0 and v1 frames, which are never correlated
+        let plain = Response::new(ResponseCode::Ok, ResponseMessage::EmptyResponse, None);
+        assert_eq!(
+            Response::from_bytes(&plain.to_bytes())
+                .unwrap()
+                .correlation_id,
+            None
+        );
+        let mut truncated = Response::new(ResponseCode::Ok, ResponseMessage::EmptyResponse, None)
+            .with_correlation_id(7)
+            .to_bytes();
+        truncated[4..8].copy_from_slice(&10u32.to_be_bytes());
+        assert_eq!(
+            Response::from_bytes(&truncated),
+            Err(ProtocolError::InconsistentLength)
+        );
+    }
+    #[test]
+    fn tcp_header_v1_rejects_inconsistent_lengths() {
+        let message = BinaryHeader::new(2, Some("new".to_string()), Some(b"data".to_vec()))
+            .unwrap()
+            .with_format(FrameFormat::V1);
+        let mut long_name = message.to_bytes();
+        long_name[12..14].copy_from_slice(&200u16.to_be_bytes());
+        assert_eq!(
+            Err(ProtocolError::InconsistentLength),
+            BinaryHeader::from_bytes(&long_name)
+        );
+        let mut short = message.to_bytes();
+        short[4..8].copy_from_slice(&13u32.to_be_bytes());
+        assert_eq!(
+            Err(ProtocolError::InconsistentLength),
+            BinaryHeader::frame_length(&short)
+        );
+    }
+    #[test]
+    fn subscribe_request_byte_test() {
+        let request = SubscribeRequest::new(Some("billing".to_string()));
+        assert_eq!(request, SubscribeRequest::from_bytes(&request.to_bytes()));
+        assert_eq!(
+            SubscribeRequest::default(),
+            SubscribeRequest::from_bytes(&[])
+        );
+        let request = SubscribeRequest {
+            group: None,
+            visibility_timeout_ms: 30_000,
+            max_wait_ms: 5_000,
+            max_messages: 100,
+        };
+        assert_eq!(request, SubscribeRequest::from_bytes(&request.to_bytes()));
+        // requests without a wait time hold no message
+        let bytes = request.to_bytes();
+        assert_eq!(
+            SubscribeRequest::from_bytes(&bytes[..bytes.len() - 8]).max_wait_ms,
+            0
+        );
+    }
+    #[test]
+    fn stream_request_byte_test() {
+        let request = StreamRequest::new(Some("billing".to_string()), 30_000, 16);
+        assert_eq!(
+            Some(request.clone()),
+            StreamRequest::from_bytes(&request.to_bytes())
+        );
+        assert_eq!(None, StreamRequest::from_bytes(&request.to_bytes()[..12]));
+    }
+    #[test]
+    fn ack_request_byte_test() {
+        let request = AckRequest::new(Some("billing".to_string()), 4 << 32 | 24);
+        assert_eq!(
+            Some(request.clone()),
+            AckRequest::from_bytes(&request.to_bytes())
+        );
+        assert_eq!(None, AckRequest::from_bytes(&request.to_bytes()[..10]));
+    }
+    #[test]
+    fn dead_letter_byte_test() {
+        let letter = DeadLetterMessage {
+            queue: "orders".to_string(),
+            group: "billing".to_string(),
+            offset: 24,
+            deliveries: 5,
+            message: b"Hello World!".to_vec(),
+        };
+        assert_eq!(
+            Some(letter.clone()),
+            DeadLetterMessage::from_bytes(&letter.to_bytes())
+        );
+    }
+    #[test]
+    fn fetch_request_byte_test() {
+        let request = FetchRequest::new(42, 10, 1 << 20);
+        assert_eq!(
+            Some(request.clone()),
+            FetchRequest::from_bytes(&request.to_bytes())
+        );
+        assert_eq!(None, FetchRequest::from_bytes(&request.to_bytes()[..12]));
+        let waiting = FetchRequest {
+            max_wait_ms: 500,
+            ..request.clone()
+        };
+        assert_eq!(
+            Some(waiting.clone()),
+            FetchRequest::from_bytes(&waiting.to_bytes())
+        );
+        assert_eq!(
+            Some(request),
+            FetchRequest::from_bytes(&waiting.to_bytes()[..16])
+        );
+    }
+    #[test]
+    fn record_batch_byte_test() {
+        let batch = RecordBatch::new(vec![
+            Record {
+                offset: 7,
+                data: b"Hello".to_vec(),
+            },
+            Record {
+                offset: 8,
+                data: Vec::new(),
+            },
+        ]);
+        let bytes = batch.to_bytes();
+        assert_eq!(Some(batch), RecordBatch::from_bytes(&bytes));
+        assert_eq!(None, RecordBatch::from_bytes(&bytes[..bytes.len() - 1]));
+        assert_eq!(
+            Some(RecordBatch::default()),
+            RecordBatch::from_bytes(&RecordBatch::default().to_bytes())
+        );
+    }
+    #[test]
+    fn publish_batch_byte_test() {
+        let batch = PublishBatch::new(vec![
+            Topic::new(1, 1718709072, b"Hello".to_vec()),
+            Topic::with_key(2, 1718709072, b"user-1".to_vec(), b"World".to_vec()),
+        ]);
+        let bytes = batch.to_bytes();
+        assert_eq!(Some(&batch), PublishBatch::from_bytes(&bytes).as_ref());
+        assert_eq!(
+            PublishBatch::records(&bytes).unwrap()[1],
+            batch.topics[1].to_bytes()
+        );
+        assert_eq!(None, PublishBatch::from_bytes(&bytes[..bytes.len() - 1]));
+    }
+    #[test]
+    fn offsets_byte_test() {
+        let offsets = vec![7, 8, 9];
+        assert_eq!(
+            Some(offsets.clone()),
+            decode_offsets(&encode_offsets(&offsets))
+        );
+        assert_eq!(None, decode_offsets(&encode_offsets(&offsets)[..12]));
+    }
+    #[test]
+    fn delivery_byte_test() {
+        let delivery = Delivery::new(12, b"Hello World!".to_vec());
+        assert_eq!(
+            Some(delivery.clone()),
+            Delivery::from_bytes(&delivery.to_bytes())
+        );
     }
 
     #[test]
     fn payload_byte_test() {
         let topic = Topic::new(1, 1718709072, b"Hello World!".to_vec());
-        assert_eq!(topic, Topic::from_bytes(&topic.to_bytes()));
+        assert_eq!(Topic::from_bytes(&topic.to_bytes()), Ok(topic));
+    }
+    #[test]
+    fn payload_byte_test_with_key() {
+        let topic = Topic::with_key(1, 1718709072, b"user-1".to_vec(), b"Hello World!".to_vec());
+        assert_eq!(Topic::from_bytes(&topic.to_bytes()), Ok(topic));
+        let tombstone = Topic::with_key(1, 1718709072, b"user-1".to_vec(), Vec::new());
+        assert!(tombstone.is_tombstone());
+        assert_eq!(Topic::from_bytes(&tombstone.to_bytes()), Ok(tombstone));
     }
     #[test]
     fn response_byte_test_with_data() {
@@ -241,11 +1701,343 @@ mod test {
             ResponseMessage::NoNewMessages,
             Some(b"No new messages".to_vec()),
         );
-        assert_eq!(message, Response::from_bytes(&message.to_bytes()));
+        assert_eq!(Response::from_bytes(&message.to_bytes()), Ok(message));
     }
     #[test]
     fn response_byte_test_without_data() {
         let message = Response::new(ResponseCode::Ok, ResponseMessage::NoNewMessages, None);
-        assert_eq!(message, Response::from_bytes(&message.to_bytes()));
+        assert_eq!(Response::from_bytes(&message.to_bytes()), Ok(message));
+    }
+    #[test]
+    fn tcp_header_rejects_malformed_frames() {
+        let message =
+            BinaryHeader::new(1, Some("new".to_string()), Some(b"data".to_vec())).unwrap();
+        let bytes = message.to_bytes();
+        assert_eq!(
+            Err(ProtocolError::ShortBuffer {
+                needed: bytes.len(),
+                got: bytes.len() - 1
+            }),
+            BinaryHeader::from_bytes(&bytes[..bytes.len() - 1])
+        );
+        // a payload longer than the whole frame
+        let mut inconsistent = bytes.clone();
+        inconsistent[8..12].copy_from_slice(&100u32.to_be_bytes());
+        assert_eq!(
+            Err(ProtocolError::InconsistentLength),
+            BinaryHeader::from_bytes(&inconsistent)
+        );
+        let mut invalid_name = bytes.clone();
+        invalid_name[12] = 0xff;
+        assert_eq!(
+            Err(ProtocolError::InvalidQueueName),
+            BinaryHeader::from_bytes(&invalid_name)
+        );
+        let mut oversize = bytes;
+        oversize[4..8].copy_from_slice(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        assert_eq!(
+            Err(ProtocolError::FrameTooLarge {
+                length: MAX_FRAME_SIZE + 1
+            }),
+            BinaryHeader::frame_length(&oversize)
+        );
+        assert_eq!(Ok(None), BinaryHeader::frame_length(&oversize[..8]));
+    }
+    #[test]
+    fn topic_and_response_reject_malformed_frames() {
+        let topic = Topic::new(1, 1718709072, b"Hello World!".to_vec()).to_bytes();
+        assert_eq!(
+            Err(ProtocolError::ShortBuffer {
+                needed: topic.len(),
+                got: 20
+            }),
+            Topic::from_bytes(&topic[..20])
+        );
+        let mut inconsistent = topic;
+        inconsistent[4..8].copy_from_slice(&8u32.to_be_bytes());
+        assert_eq!(
+            Err(ProtocolError::InconsistentLength),
+            Topic::from_bytes(&inconsistent)
+        );
+        let response = Response::new(ResponseCode::Ok, ResponseMessage::ResponseWithBody, None);
+        let mut inconsistent = response.to_bytes();
+        inconsistent[4..8].copy_from_slice(&4u32.to_be_bytes());
+        assert_eq!(
+            Err(ProtocolError::InconsistentLength),
+            Response::from_bytes(&inconsistent)
+        );
+    }
+    #[test]
+    fn server_codec_splits_pipelined_frames() {
+        let first = BinaryHeader::new(2, Some("orders".to_string()), Some(vec![7; 4096])).unwrap();
+        let second = BinaryHeader::new(3, Some("q".to_string()), None).unwrap();
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&first.to_bytes());
+        src.extend_from_slice(&second.to_bytes());
+        // the frames arrive a few bytes at a time
+        let bytes = src.split();
+        let mut codec = ServerCodec;
+        let mut frames = Vec::new();
+        for chunk in bytes.chunks(100) {
+            src.extend_from_slice(chunk);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                frames.push(frame.unwrap());
+            }
+        }
+        assert_eq!(frames, [first, second]);
+        assert!(src.is_empty());
+    }
+    #[test]
+    fn server_codec_skips_undecodable_frames() {
+        let mut invalid_name = BinaryHeader::new(2, Some("q".to_string()), None)
+            .unwrap()
+            .to_bytes();
+        invalid_name[12] = 0xff;
+        let ping = BinaryHeader::new(3, Some("q".to_string()), None).unwrap();
+        let mut src = BytesMut::from(&invalid_name[..]);
+        src.extend_from_slice(&ping.to_bytes());
+        let mut codec = ServerCodec;
+        assert_eq!(
+            Some(Err(ProtocolError::InvalidQueueName)),
+            codec.decode(&mut src).unwrap()
+        );
+        assert_eq!(Some(Ok(ping)), codec.decode(&mut src).unwrap());
+        let mut oversize = BytesMut::from(&invalid_name[..]);
+        oversize[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            codec.decode(&mut oversize),
+            Err(CodecError::Protocol(ProtocolError::FrameTooLarge { .. }))
+        ));
+    }
+    #[test]
+    fn client_codec_round_trip() {
+        let resp = Response::new(
+            ResponseCode::Ok,
+            ResponseMessage::ResponseWithBody,
+            Some(vec![1; 2048]),
+        );
+        let mut codec = ClientCodec;
+        let mut src = BytesMut::new();
+        ServerCodec.encode(resp, &mut src).unwrap();
+        let mut partial = src.split_to(1024);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(src);
+        let decoded = codec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(decoded.response_data, Some(vec![1; 2048]));
+        assert!(partial.is_empty());
+    }
+    #[test]
+    fn hello_byte_test() {
+        let hello = Hello::new(PROTOCOL_VERSION, FEATURE_BATCHING | FEATURE_HEADERS);
+        assert_eq!(Some(hello), Hello::from_bytes(&hello.to_bytes()));
+        assert_eq!(None, Hello::from_bytes(&hello.to_bytes()[..6]));
+        let mut other_protocol = hello.to_bytes();
+        other_protocol[..2].copy_from_slice(b"GE");
+        assert_eq!(None, Hello::from_bytes(&other_protocol));
+        // fields appended by later versions are ignored
+        let mut longer = hello.to_bytes();
+        longer.extend([1, 2, 3, 4]);
+        assert_eq!(Some(hello), Hello::from_bytes(&longer));
+    }
+    #[test]
+    fn pong_and_stats_byte_test() {
+        let pong = Pong {
+            server_time_ms: 1_700_000_000_000,
+            protocol_version: PROTOCOL_VERSION,
+            server_version: "0.1.0".to_string(),
+        };
+        assert_eq!(Some(pong.clone()), Pong::from_bytes(&pong.to_bytes()));
+        assert_eq!(None, Pong::from_bytes(&pong.to_bytes()[..12]));
+        let stats = Stats {
+            queues: vec![
+                QueueStats {
+                    name: "orders".to_string(),
+                    segments: 3,
+                    bytes: 4096,
+                    messages: 120,
+                    start_offset: 20,
+                    next_offset: 140,
+                    consumers: vec![
+                        ConsumerStats {
+                            group: "orders".to_string(),
+                            lag: 0,
+                            in_flight: 0,
+                        },
+                        ConsumerStats {
+                            group: "billing".to_string(),
+                            lag: 17,
+                            in_flight: 2,
+                        },
+                    ],
+                },
+                QueueStats {
+                    name: "empty".to_string(),
+                    segments: 1,
+                    bytes: 0,
+                    messages: 0,
+                    start_offset: 0,
+                    next_offset: 0,
+                    consumers: Vec::new(),
+                },
+            ],
+        };
+        let bytes = stats.to_bytes();
+        assert_eq!(Some(stats), Stats::from_bytes(&bytes));
+        assert_eq!(None, Stats::from_bytes(&bytes[..bytes.len() - 1]));
+        assert_eq!(Some(Stats::default()), Stats::from_bytes(&[0; 4]));
+    }
+    #[test]
+    fn alter_queue_byte_test() {
+        let request = AlterQueueRequest::new()
+            .with_durability(Durability::EveryNMessages(10))
+            .with_retention(RetentionPolicy {
+                max_age: Some(Duration::from_secs(60)),
+                max_bytes: None,
+            })
+            .with_max_message_size(None);
+        assert_eq!(
+            Some(request),
+            AlterQueueRequest::from_bytes(&request.to_bytes())
+        );
+        assert_eq!(
+            None,
+            AlterQueueRequest::from_bytes(&request.to_bytes()[..20])
+        );
+        // settings not named in the request are kept
+        let current = LogConfig {
+            max_message_size: Some(1024),
+            compaction: Some(CompactionPolicy {
+                tombstone_grace: Duration::from_secs(1),
+            }),
+            ..LogConfig::new(4096)
+        };
+        let altered = request.apply(&current);
+        assert_eq!(altered.durability, Durability::EveryNMessages(10));
+        assert_eq!(altered.retention.max_age, Some(Duration::from_secs(60)));
+        assert_eq!(altered.max_message_size, None);
+        assert_eq!(altered.segment_size, 4096);
+        assert_eq!(altered.compaction, current.compaction);
+    }
+    #[test]
+    fn queue_admin_byte_test() {
+        let names = vec!["orders".to_string(), "orders.dlq".to_string()];
+        assert_eq!(
+            Some(names.clone()),
+            decode_queue_names(&encode_queue_names(&names))
+        );
+        assert_eq!(Some(vec![]), decode_queue_names(&encode_queue_names(&[])));
+        assert_eq!(None, decode_queue_names(&encode_queue_names(&names)[..10]));
+        let description = QueueDescription {
+            settings: QueueSettings {
+                created_at: 1_700_000_000_000,
+                config: LogConfig::new(4096),
+            },
+            stats: QueueStats {
+                name: "orders".to_string(),
+                segments: 2,
+                bytes: 100,
+                messages: 10,
+                start_offset: 0,
+                next_offset: 10,
+                consumers: vec![ConsumerStats {
+                    group: "billing".to_string(),
+                    lag: 4,
+                    in_flight: 1,
+                }],
+            },
+        };
+        let bytes = description.to_bytes();
+        assert_eq!(Some(description), QueueDescription::from_bytes(&bytes));
+        assert_eq!(
+            None,
+            QueueDescription::from_bytes(&bytes[..QUEUE_SETTINGS_SIZE])
+        );
+    }
+    #[test]
+    fn hello_compatibility_policy() {
+        // a client of the same version gets the features both sides support
+        let agreed = Hello::new(PROTOCOL_VERSION, u32::MAX).negotiate().unwrap();
+        assert_eq!(agreed, Hello::new(PROTOCOL_VERSION, SUPPORTED_FEATURES));
+        // a newer client is answered with the server's version
+        let newer = Hello::new(PROTOCOL_VERSION + 1, FEATURE_BATCHING);
+        assert_eq!(newer.negotiate().unwrap().version, PROTOCOL_VERSION);
+        // clients older than the oldest supported version are refused
+        assert_eq!(None, Hello::new(MIN_PROTOCOL_VERSION - 1, 0).negotiate());
+        // clients of version 1 keep sending v0 frames and clients of version 2 v1 frames
+        assert_eq!(
+            Hello::new(2, 0).negotiate().unwrap().frame_format(),
+            FrameFormat::V1
+        );
+        assert_eq!(
+            Hello::new(1, 0).negotiate().unwrap().frame_format(),
+            FrameFormat::V0
+        );
+        assert_eq!(agreed.frame_format(), FrameFormat::V2);
+        // features the server doesn't implement are never agreed on
+        let compressed = Hello::new(PROTOCOL_VERSION, FEATURE_COMPRESSION).negotiate();
+        assert!(!compressed.unwrap().supports(FEATURE_COMPRESSION));
+    }
+
+    proptest! {
+        // decoding arbitrary bytes returns an error instead of panicking
+        #[test]
+        fn decoders_never_panic(data in proptest::collection::vec(any::<u8>(), 0..256)) {
+            let _ = BinaryHeader::frame_length(&data);
+            let _ = BinaryHeader::from_bytes(&data);
+            let _ = Topic::from_bytes(&data);
+            let _ = Response::frame_length(&data);
+            let _ = Response::from_bytes(&data);
+            let _ = SubscribeRequest::from_bytes(&data);
+            let _ = StreamRequest::from_bytes(&data);
+            let _ = AckRequest::from_bytes(&data);
+            let _ = Delivery::from_bytes(&data);
+            let _ = DeadLetterMessage::from_bytes(&data);
+            let _ = FetchRequest::from_bytes(&data);
+            let _ = RecordBatch::from_bytes(&data);
+            let _ = PublishBatch::from_bytes(&data);
+            let _ = decode_offsets(&data);
+            let _ = Pong::from_bytes(&data);
+            let _ = Stats::from_bytes(&data);
+            let _ = AlterQueueRequest::from_bytes(&data);
+            let _ = QueueDescription::from_bytes(&data);
+            let _ = decode_queue_names(&data);
+        }
+        // a valid header followed by arbitrary lengths and bytes
+        #[test]
+        fn tcp_header_never_panics(
+            command in any::<u32>(),
+            length in any::<u32>(),
+            payload_length in any::<u32>(),
+            rest in proptest::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let mut data = Vec::new();
+            data.extend(command.to_be_bytes());
+            data.extend(length.to_be_bytes());
+            data.extend(payload_length.to_be_bytes());
+            data.extend(rest);
+            if let Ok(header) = BinaryHeader::from_bytes(&data) {
+                prop_assert!(header.length as usize <= data.len());
+            }
+        }
+        #[test]
+        fn tcp_header_round_trip(
+            command in any::<u32>(),
+            queue_name in "[a-z.-]{1,32}",
+            payload in proptest::option::of(proptest::collection::vec(any::<u8>(), 1..128)),
+            format in prop_oneof![
+                Just(FrameFormat::V0),
+                Just(FrameFormat::V1),
+                Just(FrameFormat::V2),
+            ],
+            correlation_id in any::<u32>(),
+        ) {
+            let command = command & !(FRAME_V1 | FRAME_V2);
+            let mut message = BinaryHeader::new(command, Some(queue_name), payload).unwrap();
+            if format == FrameFormat::V2 {
+                message.correlation_id = correlation_id;
+            }
+            let message = message.with_format(format);
+            prop_assert_eq!(Ok(message.clone()), BinaryHeader::from_bytes(&message.to_bytes()));
+        }
     }
 }
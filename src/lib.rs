@@ -1,25 +1,212 @@
-use internal::log::{CommitLog, StorageError, SEGMENT_SIZE};
-use std::borrow::BorrowMut;
-use std::collections::HashMap;
+use futures::{SinkExt, StreamExt};
+use internal::log::{
+    valid_name, CommitLog, DeadLetter, LogConfig, QueueSettings, RecordId, RecordKey, StorageError,
+};
+use std::collections::{hash_map, HashMap, VecDeque};
 use std::fmt::Debug;
+use std::future::Future;
 use std::io::ErrorKind;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, PoisonError};
+use std::time::{Duration, SystemTime};
 use std::{io, result};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify, Semaphore};
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::time::{self, Instant};
+use tokio_util::codec::{Decoder, FramedRead, FramedWrite};
 use tracing::{error, info};
 
 pub mod internal;
 
 pub use crate::internal::commands::*;
+pub use crate::internal::config::*;
 pub use crate::internal::protocol::*;
 
 pub type Result<T, E> = result::Result<T, E>;
-const DIR_PATH: &str = "storage/queue/";
+// consumer group used to move dead letters back into their queue
+const REPLAY_GROUP: &str = "replay";
+// held requests a connection may have waiting for messages at once, reading its
+// next request waits for one of them to be answered
+const MAX_WAITING_REQUESTS: usize = 64;
+// responses and pushed messages are written as whole frames one at a time
+type ResponseWriter = Arc<Mutex<FramedWrite<OwnedWriteHalf, ServerCodec>>>;
+
 pub struct Server {
-    pub stream: Arc<TcpStream>,
-    messages: Arc<RwLock<HashMap<String, CommitLog>>>,
+    writer: ResponseWriter,
+    queues: Arc<Queues>,
+    notifiers: Arc<Notifiers>,
+    // the streaming subscriptions of the connection by queue
+    streams: HashMap<String, Subscription>,
+    // the version and features agreed on by the connection's HELLO
+    hello: Option<Hello>,
+    // the correlation id of the request being answered
+    correlation_id: Option<u32>,
+    // the client said goodbye, nothing else is read from the connection
+    quit: bool,
+    // requests answered from their own task, aborted when the connection closes
+    waiting: JoinSet<()>,
+}
+
+// wakes up the subscribers of a queue when messages are appended to it
+#[derive(Debug, Default)]
+pub struct Notifiers {
+    queues: std::sync::Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl Notifiers {
+    pub fn queue(&self, name: &str) -> Arc<Notify> {
+        let mut queues = self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+        queues.entry(name.to_owned()).or_default().clone()
+    }
+    pub fn notify(&self, name: &str) {
+        let queues = self.queues.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(notify) = queues.get(name) {
+            notify.notify_waiters();
+        }
+    }
+}
+
+// the queues of the broker. The map is only locked to look up or create a queue, every
+// queue has its own lock so a slow append to one queue doesn't hold up the others
+pub struct Queues {
+    queues: std::sync::RwLock<HashMap<String, Arc<Mutex<CommitLog>>>>,
+    // the config new and restored queues get
+    config: LogConfig,
+    dir_path: String,
+    // whether publishing to an unknown queue creates it
+    auto_create: bool,
+}
+
+impl Default for Queues {
+    fn default() -> Self {
+        let config = BrokerConfig::default();
+        Queues::new(config.log_config(), &config.data_dir)
+    }
+}
+
+impl Queues {
+    pub fn new(config: LogConfig, dir_path: &str) -> Self {
+        Queues {
+            queues: std::sync::RwLock::new(HashMap::new()),
+            config,
+            // queue directories are appended to the path
+            dir_path: match dir_path.is_empty() || dir_path.ends_with('/') {
+                true => dir_path.to_owned(),
+                false => format!("{dir_path}/"),
+            },
+            auto_create: true,
+        }
+    }
+    pub fn with_auto_create(mut self, auto_create: bool) -> Self {
+        self.auto_create = auto_create;
+        self
+    }
+    pub fn auto_create(&self) -> bool {
+        self.auto_create
+    }
+    /// the config queues are created with
+    pub fn config(&self) -> LogConfig {
+        self.config
+    }
+    pub fn get(&self, name: &str) -> Option<Arc<Mutex<CommitLog>>> {
+        let queues = self.queues.read().unwrap_or_else(PoisonError::into_inner);
+        queues.get(name).cloned()
+    }
+    /// the queue with the given name, created on first use
+    pub fn get_or_create(&self, name: &str) -> Result<Arc<Mutex<CommitLog>>, StorageError> {
+        if let Some(queue) = self.get(name) {
+            return Ok(queue);
+        }
+        let mut queues = self.queues.write().unwrap_or_else(PoisonError::into_inner);
+        match queues.entry(name.to_owned()) {
+            hash_map::Entry::Occupied(entry) => Ok(entry.get().clone()),
+            hash_map::Entry::Vacant(entry) => {
+                let log = CommitLog::new(name, self.config, &self.dir_path)?;
+                Ok(entry.insert(Arc::new(Mutex::new(log))).clone())
+            }
+        }
+    }
+    /// create a queue with its own config, fails when the queue already exists
+    pub fn create(
+        &self,
+        name: &str,
+        config: LogConfig,
+    ) -> Result<Arc<Mutex<CommitLog>>, StorageError> {
+        if !valid_name(name) {
+            return Err(StorageError::InvalidName);
+        }
+        config.validate()?;
+        let mut queues = self.queues.write().unwrap_or_else(PoisonError::into_inner);
+        if queues.contains_key(name) {
+            return Err(StorageError::QueueExists);
+        }
+        let queue = Arc::new(Mutex::new(CommitLog::new(name, config, &self.dir_path)?));
+        queues.insert(name.to_owned(), queue.clone());
+        Ok(queue)
+    }
+    /// remove a queue and its data from disk, returns false when there is no such queue.
+    /// The queue stays locked while its directory is removed and only leaves the map
+    /// once the files are gone, so it can't be created again in the meantime
+    pub async fn delete(&self, name: &str) -> Result<bool, StorageError> {
+        let Some(queue) = self.get(name) else {
+            return Ok(false);
+        };
+        let log = queue.clone().lock_owned().await;
+        // deleted by another request while waiting for the queue's lock
+        if log.is_deleted() {
+            return Ok(false);
+        }
+        let (log, deleted) = task::spawn_blocking(move || {
+            let mut log = log;
+            let deleted = log.delete();
+            (log, deleted)
+        })
+        .await
+        .expect("deleting queue files");
+        deleted?;
+        let mut queues = self.queues.write().unwrap_or_else(PoisonError::into_inner);
+        if queues.get(name).is_some_and(|q| Arc::ptr_eq(q, &queue)) {
+            queues.remove(name);
+        }
+        drop(log);
+        Ok(true)
+    }
+    /// the names of the queues, sorted
+    pub fn names(&self) -> Vec<String> {
+        let queues = self.queues.read().unwrap_or_else(PoisonError::into_inner);
+        let mut names: Vec<String> = queues.keys().cloned().collect();
+        names.sort();
+        names
+    }
+    pub fn insert(&self, log: CommitLog) {
+        let mut queues = self.queues.write().unwrap_or_else(PoisonError::into_inner);
+        queues.insert(log.name.to_owned(), Arc::new(Mutex::new(log)));
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        let queues = self.queues.read().unwrap_or_else(PoisonError::into_inner);
+        queues.contains_key(name)
+    }
+    /// every queue of the broker, the map isn't locked while the queues are used
+    pub fn all(&self) -> Vec<(String, Arc<Mutex<CommitLog>>)> {
+        let queues = self.queues.read().unwrap_or_else(PoisonError::into_inner);
+        queues
+            .iter()
+            .map(|(name, queue)| (name.to_owned(), queue.clone()))
+            .collect()
+    }
+}
+
+// a read of a request that is held until there are messages
+type ReadFuture<'a, T> = Pin<Box<dyn Future<Output = Result<Option<T>, StorageError>> + Send + 'a>>;
+
+// a long lived subscription pushing messages to the connection
+struct Subscription {
+    // how many more messages the subscriber is ready to receive
+    credits: Arc<Semaphore>,
+    task: JoinHandle<()>,
 }
 
 #[derive(Debug)]
@@ -45,31 +232,148 @@ impl From<io::Error> for ServerError {
     }
 }
 
-async fn send_response_ok(
-    stream: &mut Arc<TcpStream>,
-    resp_message: ResponseMessage,
-    data: Option<Vec<u8>>,
-) -> Result<usize, ServerError> {
-    let resp = Response::new(ResponseCode::Ok, resp_message, data);
-    let usize = stream.try_write(&resp.to_bytes())?;
-    Ok(usize)
+// the queue holding the messages of a queue that ran out of delivery attempts
+pub fn dead_letter_queue(queue_name: &str) -> String {
+    format!("{queue_name}.dlq")
+}
+
+// the compaction key of a stored topic, topics published without a key are never compacted
+fn topic_key(data: &[u8]) -> Option<RecordKey> {
+    let topic = Topic::from_bytes(data).ok()?;
+    let tombstone = topic.is_tombstone();
+    topic.key.map(|key| RecordKey { key, tombstone })
+}
+
+// the storage and consumer lag of a queue as reported by STATS
+fn queue_stats(log: &CommitLog) -> QueueStats {
+    let mut groups = log.groups();
+    groups.sort();
+    QueueStats {
+        name: log.name.clone(),
+        segments: log.segments.len() as u32,
+        bytes: log.size(),
+        messages: log.message_count(),
+        start_offset: log.start_offset(),
+        next_offset: log.next_offset(),
+        consumers: groups
+            .into_iter()
+            .map(|group| ConsumerStats {
+                lag: log.lag(&group).unwrap_or_default(),
+                in_flight: log.in_flight(&group) as u32,
+                group,
+            })
+            .collect(),
+    }
+}
+
+// milliseconds since the unix epoch
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// whether a request is held until there are messages when there are none
+fn may_wait(header: &BinaryHeader) -> bool {
+    let payload = header.payload.as_deref().unwrap_or_default();
+    match Commands::from_u32(header.command) {
+        Commands::SUBSCRIBE => SubscribeRequest::from_bytes(payload).max_wait_ms > 0,
+        Commands::FETCH => FetchRequest::from_bytes(payload).is_some_and(|r| r.max_wait_ms > 0),
+        _ => false,
+    }
+}
+
+// tag a response with the correlation id of the request it answers
+fn correlate(resp: Response, correlation_id: Option<u32>) -> Response {
+    match correlation_id {
+        Some(correlation_id) => resp.with_correlation_id(correlation_id),
+        None => resp,
+    }
 }
 
-async fn send_response_err(
-    stream: &mut Arc<TcpStream>,
-    resp_message: ResponseMessage,
-    data: Option<Vec<u8>>,
-) -> Result<usize, ServerError> {
-    let resp = Response::new(ResponseCode::Err, resp_message, data);
-    let usize = stream.try_write(&resp.to_bytes())?;
-    Ok(usize)
+// write a whole response, the lock keeps frames pushed by subscriptions from being
+// interleaved with responses
+async fn send_response(writer: &ResponseWriter, resp: Response) -> Result<(), ServerError> {
+    let mut writer = writer.lock().await;
+    writer.send(resp).await.map_err(io::Error::from)?;
+    Ok(())
 }
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        for subscription in self.streams.values() {
+            subscription.task.abort();
+        }
+    }
+}
+
 impl Server {
-    pub fn new(
-        stream: Arc<TcpStream>,
-        messages: Arc<RwLock<HashMap<String, CommitLog>>>,
-    ) -> Server {
-        Server { stream, messages }
+    /// a server answering requests on the write half of a connection, the read half
+    /// is decoded with a `ServerCodec`
+    pub fn new(writer: OwnedWriteHalf, queues: Arc<Queues>, notifiers: Arc<Notifiers>) -> Server {
+        Server {
+            writer: Arc::new(Mutex::new(FramedWrite::new(writer, ServerCodec))),
+            queues,
+            notifiers,
+            streams: HashMap::new(),
+            hello: None,
+            correlation_id: None,
+            quit: false,
+            waiting: JoinSet::new(),
+        }
+    }
+    /// serve the requests of a connection until the client quits or disconnects, or
+    /// `shutdown` changes
+    pub async fn handle_connection(
+        stream: TcpStream,
+        queues: Arc<Queues>,
+        notifiers: Arc<Notifiers>,
+        mut shutdown: watch::Receiver<bool>,
+    ) {
+        let (reader, writer) = stream.into_split();
+        let mut requests = FramedRead::new(reader, ServerCodec);
+        let mut server = Server::new(writer, queues, notifiers);
+        while !server.has_quit() {
+            let request = tokio::select! {
+                request = requests.next() => request,
+                _ = shutdown.changed() => {
+                    // requests already read from the socket are answered before closing
+                    let mut buffered = std::mem::take(requests.read_buffer_mut());
+                    while let Ok(Some(request)) = ServerCodec.decode(&mut buffered) {
+                        if !server.serve_frame(Ok(request)).await || server.has_quit() {
+                            break;
+                        }
+                    }
+                    return;
+                }
+            };
+            let Some(request) = request else {
+                return;
+            };
+            if !server.serve_frame(request).await {
+                return;
+            }
+        }
+    }
+    // answer a frame read from the connection, false when the connection has to be closed
+    async fn serve_frame(
+        &mut self,
+        request: result::Result<result::Result<BinaryHeader, ProtocolError>, CodecError>,
+    ) -> bool {
+        match request {
+            Ok(Ok(tcp_header)) => self.handle_request(tcp_header).await,
+            Ok(Err(e)) => self.reject_frame(e).await,
+            Err(CodecError::Protocol(e)) => {
+                // the stream can't be split into frames anymore
+                self.reject_frame(e).await;
+                return false;
+            }
+            Err(CodecError::Io(e)) => {
+                error!("ERROR: Got an unexpected error: {:?}", e);
+                return false;
+            }
+        }
+        true
     }
     pub async fn decode_buffer(
         &mut self,
@@ -80,173 +384,1687 @@ impl Server {
         self.handle_client_command(command, payload, queue_name)
             .await
     }
+    /// answer a request, responses echo the request's correlation id when it has one.
+    /// Correlated requests that may be held waiting for messages are answered from
+    /// their own task so they don't hold up the requests sent after them
+    pub async fn handle_request(&mut self, header: BinaryHeader) {
+        let correlation_id = (header.format == FrameFormat::V2).then_some(header.correlation_id);
+        if correlation_id.is_some() && self.hello.is_some() && may_wait(&header) {
+            let mut server = Server {
+                writer: self.writer.clone(),
+                queues: self.queues.clone(),
+                notifiers: self.notifiers.clone(),
+                streams: HashMap::new(),
+                hello: self.hello,
+                correlation_id,
+                quit: false,
+                waiting: JoinSet::new(),
+            };
+            while self.waiting.try_join_next().is_some() {}
+            if self.waiting.len() >= MAX_WAITING_REQUESTS {
+                self.waiting.join_next().await;
+            }
+            self.waiting.spawn(async move {
+                server
+                    .handle_client_command(header.command, header.payload, header.queue_name)
+                    .await
+            });
+            return;
+        }
+        self.correlation_id = correlation_id;
+        self.handle_client_command(header.command, header.payload, header.queue_name)
+            .await;
+        self.correlation_id = None;
+    }
+    /// whether the client sent QUIT, the connection should be closed
+    pub fn has_quit(&self) -> bool {
+        self.quit
+    }
+    /// answer a frame that couldn't be decoded
+    pub async fn reject_frame(&mut self, e: ProtocolError) {
+        error!("ERROR: Invalid frame: {e}");
+        self.respond_err(
+            ResponseMessage::InvalidFrame,
+            Some(e.to_string().into_bytes()),
+        )
+        .await;
+    }
     pub async fn handle_client_command(
         &mut self,
         command: u32,
         data: Option<Vec<u8>>,
         queue_name: Option<String>,
     ) {
-        if queue_name.is_none() {
-            if let Err(e) =
-                send_response_err(&mut self.stream, ResponseMessage::QueueNameRequired, None).await
-            {
-                error!("ERROR: Failed to write response to stream: {:?}", e);
-                return;
+        match Commands::from_u32(command) {
+            Commands::HELLO => return self.handshake(&data.unwrap_or_default()).await,
+            Commands::QUIT => {
+                self.quit = true;
+                return self.respond_ok(ResponseMessage::Goodbye, None).await;
             }
+            _ => {}
         }
-        match Commands::from_u32(command) {
-            Commands::QUIT => {}
+        let Some(hello) = self.hello else {
+            self.respond_err(ResponseMessage::HandshakeRequired, None)
+                .await;
+            return;
+        };
+        let command = Commands::from_u32(command);
+        let queue_required = !matches!(
+            command,
+            Commands::PING | Commands::STATS | Commands::LIST_QUEUES
+        );
+        if queue_name.is_none() && queue_required {
+            self.respond_err(ResponseMessage::QueueNameRequired, None)
+                .await;
+            return;
+        }
+        match command {
+            Commands::PING => {
+                let pong = Pong {
+                    server_time_ms: unix_millis(SystemTime::now()),
+                    protocol_version: hello.version,
+                    server_version: env!("CARGO_PKG_VERSION").to_string(),
+                };
+                self.respond_ok(ResponseMessage::ResponseWithPong, Some(pong.to_bytes()))
+                    .await;
+            }
+            Commands::STATS => {
+                // stats of every queue unless the request names one
+                let names = match queue_name.filter(|name| !name.is_empty()) {
+                    Some(name) if !self.queues.contains(&name) => {
+                        return self.respond_queue_not_found(&name).await;
+                    }
+                    Some(name) => vec![name],
+                    None => self.queues.names(),
+                };
+                let mut stats = Stats::default();
+                for name in names {
+                    if let Some(queue) = self.queues.get(&name) {
+                        stats.queues.push(queue_stats(&*queue.lock().await));
+                    }
+                }
+                self.respond_ok(ResponseMessage::ResponseWithStats, Some(stats.to_bytes()))
+                    .await;
+            }
             Commands::SUBSCRIBE => {
                 let name = queue_name.unwrap();
-                let mut message_map = self.messages.write().await;
-                if let Some(commit_log) = message_map.get_mut(&name) {
-                    let data = match commit_log.read() {
-                        Ok(data) => Some(data),
-                        Err(_) => None,
-                    };
-                    let mut message = ResponseMessage::ResponseWithBody;
-                    if data.is_none() || data.clone().unwrap().is_empty() {
-                        message = ResponseMessage::NoNewMessages;
-                    }
-                    if let Err(e) = send_response_ok(&mut self.stream, message, data).await {
-                        error!("ERROR: Failed to write response to stream: {:?}", e);
-                        return;
+                let request = SubscribeRequest::from_bytes(&data.unwrap_or_default());
+                if request.max_messages > 0 && !hello.supports(FEATURE_BATCHING) {
+                    return self.respond_feature_required("batching").await;
+                }
+                self.subscribe(&name, request).await;
+            }
+            Commands::ACK | Commands::NACK => {
+                let name = queue_name.unwrap();
+                let nack = matches!(command, Commands::NACK);
+                let Some(request) = AckRequest::from_bytes(&data.unwrap_or_default()) else {
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
+                };
+                let Some(queue) = self.queues.get(&name) else {
+                    return self.respond_queue_not_found(&name).await;
+                };
+                let group = request.group.unwrap_or_else(|| name.clone());
+                let id = RecordId::from_u64(request.id);
+                let result = match nack {
+                    true => queue.lock().await.nack(&group, id),
+                    false => queue.lock().await.ack(&group, id),
+                };
+                match result {
+                    Ok(_) => {
+                        self.respond_ok(ResponseMessage::EmptyResponse, None).await;
+                    }
+                    Err(e) => {
+                        let body = format!("failed to acknowledge {}: {e}", request.id);
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
+                    }
+                }
+            }
+            Commands::REPLAY => {
+                let name = queue_name.unwrap();
+                if !self.queues.contains(&name) {
+                    return self.respond_queue_not_found(&name).await;
+                }
+                match self.replay_dead_letters(&name).await {
+                    Ok(replayed) => {
+                        self.notifiers.notify(&name);
+                        info!("INFO: REPLAYED {replayed} DEAD LETTERS INTO TOPIC:{name}");
+                        self.respond_ok(
+                            ResponseMessage::ResponseWithBody,
+                            Some(replayed.to_be_bytes().to_vec()),
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        let body = format!("failed to replay dead letters of {name}: {e}");
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
+                    }
+                }
+            }
+            Commands::FETCH => {
+                let name = queue_name.unwrap();
+                let Some(request) = FetchRequest::from_bytes(&data.unwrap_or_default()) else {
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
+                };
+                let result = self.fetch(&name, &request).await;
+                match result {
+                    Ok(records) => {
+                        let batch = RecordBatch::new(
+                            records
+                                .into_iter()
+                                .map(|(offset, data)| Record { offset, data })
+                                .collect(),
+                        );
+                        self.respond_ok(ResponseMessage::ResponseWithBatch, Some(batch.to_bytes()))
+                            .await;
+                    }
+                    Err(StorageError::CorruptRecord { segment, offset }) => {
+                        error!("ERROR: CORRUPT RECORD IN TOPIC:{name} SEGMENT:{segment} OFFSET:{offset}");
+                        self.respond_err(ResponseMessage::CorruptRecord, None).await;
+                    }
+                    Err(e) => {
+                        let body = format!("failed to fetch from {name}: {e}");
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
+                    }
+                }
+            }
+            Commands::SEEK => {
+                // resolves the first offset at or after a time, FETCH reads from there
+                let name = queue_name.unwrap();
+                let Some(timestamp) = data
+                    .as_deref()
+                    .and_then(|data| data.get(..8))
+                    .map(|data| u64::from_be_bytes(data.try_into().unwrap()))
+                else {
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
+                };
+                let Some(queue) = self.queues.get(&name) else {
+                    return self.respond_queue_not_found(&name).await;
+                };
+                let offset = queue.lock().await.offset_for_time(timestamp);
+                self.respond_ok(
+                    ResponseMessage::ResponseWithBody,
+                    Some(offset.to_be_bytes().to_vec()),
+                )
+                .await;
+            }
+            Commands::ALTER_QUEUE => {
+                let name = queue_name.unwrap();
+                let Some(request) = AlterQueueRequest::from_bytes(&data.unwrap_or_default()) else {
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
+                };
+                let Some(queue) = self.queues.get(&name) else {
+                    return self.respond_queue_not_found(&name).await;
+                };
+                let result = {
+                    let mut log = queue.lock().await;
+                    let config = request.apply(&log.config());
+                    log.set_config(config).map(|_| log.settings())
+                };
+                match result {
+                    Ok(settings) => {
+                        info!("INFO: ALTERED TOPIC:{name} SETTINGS:{:?}", settings.config);
+                        self.respond_ok(
+                            ResponseMessage::ResponseWithSettings,
+                            Some(settings.to_bytes()),
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        let body = format!("failed to alter {name}: {e}");
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
+                    }
+                }
+            }
+            Commands::CREATE_QUEUE => {
+                // settings left out of the request are the broker's defaults
+                let name = queue_name.unwrap();
+                let request = match data.filter(|data| !data.is_empty()) {
+                    Some(data) => AlterQueueRequest::from_bytes(&data),
+                    None => Some(AlterQueueRequest::new()),
+                };
+                let Some(request) = request else {
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
+                };
+                let config = request.apply(&self.queues.config());
+                let result = match self.queues.create(&name, config) {
+                    Ok(queue) => Ok(queue.lock().await.settings()),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(settings) => {
+                        info!("INFO: CREATED TOPIC:{name} SETTINGS:{:?}", settings.config);
+                        self.respond_ok(
+                            ResponseMessage::ResponseWithSettings,
+                            Some(settings.to_bytes()),
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        let body = format!("failed to create {name}: {e}");
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
+                    }
+                }
+            }
+            Commands::DELETE_QUEUE => {
+                let name = queue_name.unwrap();
+                match self.queues.delete(&name).await {
+                    Ok(true) => {
+                        // held subscribers wake up and find the queue gone
+                        self.notifiers.notify(&name);
+                        info!("INFO: DELETED TOPIC:{name}");
+                        self.respond_ok(ResponseMessage::EmptyResponse, None).await;
+                    }
+                    Ok(false) => self.respond_queue_not_found(&name).await,
+                    Err(e) => {
+                        error!("ERROR: Failed to delete {name}: {e}");
+                        let body = format!("failed to delete {name}: {e}");
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
+                    }
+                }
+            }
+            Commands::LIST_QUEUES => {
+                let names = self.queues.names();
+                self.respond_ok(
+                    ResponseMessage::ResponseWithQueues,
+                    Some(encode_queue_names(&names)),
+                )
+                .await;
+            }
+            Commands::DESCRIBE_QUEUE => {
+                let name = queue_name.unwrap();
+                let Some(queue) = self.queues.get(&name) else {
+                    return self.respond_queue_not_found(&name).await;
+                };
+                let description = {
+                    let log = queue.lock().await;
+                    QueueDescription {
+                        settings: log.settings(),
+                        stats: queue_stats(&log),
+                    }
+                };
+                self.respond_ok(
+                    ResponseMessage::ResponseWithDescription,
+                    Some(description.to_bytes()),
+                )
+                .await;
+            }
+            Commands::PURGE_QUEUE | Commands::TRUNCATE_QUEUE => {
+                // truncating drops the messages before the offset in the payload
+                let name = queue_name.unwrap();
+                let offset = match command {
+                    Commands::TRUNCATE_QUEUE => data
+                        .as_deref()
+                        .and_then(|data| data.get(..8))
+                        .map(|data| u64::from_be_bytes(data.try_into().unwrap())),
+                    _ => Some(u64::MAX),
+                };
+                let Some(offset) = offset else {
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
+                };
+                let Some(queue) = self.queues.get(&name) else {
+                    return self.respond_queue_not_found(&name).await;
+                };
+                let result = queue.lock().await.truncate_before(offset);
+                match result {
+                    Ok(removed) => {
+                        info!("INFO: REMOVED {removed} MESSAGES FROM TOPIC:{name}");
+                        self.respond_ok(
+                            ResponseMessage::ResponseWithBody,
+                            Some(removed.to_be_bytes().to_vec()),
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        error!("ERROR: Failed to truncate {name}: {e}");
+                        let body = format!("failed to truncate {name}: {e}");
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
+                    }
+                }
+            }
+            Commands::STREAM => {
+                let name = queue_name.unwrap();
+                let Some(request) = StreamRequest::from_bytes(&data.unwrap_or_default()) else {
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
+                };
+                self.stream(&name, request).await;
+            }
+            Commands::CREDIT => {
+                // credits are not answered so they don't get mixed up with pushed messages
+                let name = queue_name.unwrap();
+                let credits = data
+                    .as_deref()
+                    .and_then(|data| data.get(..4))
+                    .map(|credits| u32::from_be_bytes(credits.try_into().unwrap()))
+                    .unwrap_or(0);
+                if let Some(subscription) = self.streams.get(&name) {
+                    subscription.credits.add_permits(credits as usize);
+                }
+            }
+            Commands::PUBLISH_BATCH => {
+                if !hello.supports(FEATURE_BATCHING) {
+                    return self.respond_feature_required("batching").await;
+                }
+                let name = queue_name.unwrap();
+                if !self.queues.auto_create() && !self.queues.contains(&name) {
+                    return self.respond_queue_not_found(&name).await;
+                }
+                let data = data.unwrap_or_default();
+                let Some(records) = PublishBatch::records(&data) else {
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
+                };
+                match self.save_batch_to_queue(&name, &records).await {
+                    Ok(offsets) => {
+                        info!("INFO: PUBLISHED {} MESSAGES TO TOPIC:{name}", offsets.len());
+                        self.respond_ok(
+                            ResponseMessage::ResponseWithOffsets,
+                            Some(encode_offsets(&offsets)),
+                        )
+                        .await;
+                    }
+                    Err(e) => {
+                        error!("ERROR: Failed to publish batch to {name}: {e}");
+                        let body = format!("failed to publish to {name}: {e}");
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
                     }
-                    info!("INFO: SUBSCRIBED TO TOPIC: {name}");
-                } else {
-                    info!("WARN: No commit log found for topic: {name}");
                 }
             }
             Commands::PUBLISH => {
                 if data.is_none() {
-                    if let Err(e) = send_response_err(
-                        &mut self.stream,
-                        ResponseMessage::MessageBodyRequired,
-                        None,
-                    )
-                    .await
-                    {
-                        error!("ERROR: Failed to write response to stream: {:?}", e);
-                        return;
-                    }
+                    self.respond_err(ResponseMessage::MessageBodyRequired, None)
+                        .await;
+                    return;
                 }
                 let name = queue_name.unwrap();
-                let queue_exists = self.messages.read().await.contains_key(&name);
-                match queue_exists {
-                    true => {
-                        self.save_to_queue(&name, &data.unwrap()).await;
-                        info!("INFO: PUBLISHED MESSAGE TO TOPIC:{name}");
+                let queue_exists = self.queues.contains(&name);
+                if !queue_exists && !self.queues.auto_create() {
+                    return self.respond_queue_not_found(&name).await;
+                }
+                // the append returns once the message is as durable as the queue's
+                // durability mode asks for, so the ack is only sent after that
+                let saved = match queue_exists {
+                    true => self.save_to_queue(&name, &data.unwrap()).await,
+                    false => self.create_new_queue(&name, &data.unwrap()).await,
+                };
+                match saved {
+                    Ok(offset) => {
+                        info!("INFO: PUBLISHED MESSAGE TO TOPIC:{name} OFFSET:{offset}");
+                        self.respond_ok(ResponseMessage::EmptyResponse, None).await;
                     }
-                    false => {
-                        match self.create_new_queue(&name, &data.unwrap()).await {
-                            Ok(_) => info!("INFO: PUBLISHED MESSAGE TO NEW TOPIC:{name}"),
-                            Err(e) => error!("{:?}", e),
-                        };
+                    Err(e) => {
+                        error!("ERROR: Failed to publish to {name}: {e}");
+                        let body = format!("failed to publish to {name}: {e}");
+                        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                            .await;
                     }
                 }
-                if let Err(e) =
-                    send_response_ok(&mut self.stream, ResponseMessage::EmptyResponse, None).await
+            }
+            _ => {
+                error!("NO SUCH COMMAND");
+            }
+        }
+    }
+    // agree on the protocol version and features of the connection, every other request
+    // is refused until this succeeded
+    async fn handshake(&mut self, data: &[u8]) {
+        match Hello::from_bytes(data).and_then(|request| request.negotiate()) {
+            Some(hello) => {
+                info!(
+                    "INFO: HANDSHAKE VERSION:{} FEATURES:{:#x}",
+                    hello.version, hello.features
+                );
+                self.hello = Some(hello);
+                self.respond_ok(ResponseMessage::ResponseWithHello, Some(hello.to_bytes()))
+                    .await;
+            }
+            None => {
+                let mut versions = MIN_PROTOCOL_VERSION.to_be_bytes().to_vec();
+                versions.extend(PROTOCOL_VERSION.to_be_bytes());
+                self.respond_err(ResponseMessage::UnsupportedVersion, Some(versions))
+                    .await;
+            }
+        }
+    }
+    async fn respond_queue_not_found(&mut self, name: &str) {
+        let body = format!("no such queue {name}");
+        self.respond_err(ResponseMessage::QueueNotFound, Some(body.into_bytes()))
+            .await;
+    }
+    async fn respond_feature_required(&mut self, feature: &str) {
+        let body = format!("{feature} was not negotiated");
+        self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+            .await;
+    }
+    // hand out the next message of a queue to a consumer group, messages are kept in
+    // flight until acknowledged when the request sets a visibility timeout
+    async fn subscribe(&mut self, name: &str, request: SubscribeRequest) {
+        let group = request.group.clone().unwrap_or_else(|| name.to_owned());
+        if request.max_messages > 0 && request.visibility_timeout_ms == 0 {
+            return self.subscribe_batch(name, &group, &request).await;
+        }
+        let visibility_timeout_ms = request.visibility_timeout_ms;
+        let (queues, notifiers, group_name) = (&self.queues, &self.notifiers, &group);
+        let read = || -> ReadFuture<_> {
+            Box::pin(async move {
+                match Self::next_message(queues, notifiers, name, group_name, visibility_timeout_ms)
+                    .await
                 {
-                    error!("ERROR: Failed to write response to stream: {:?}", e);
+                    Ok(message) => Ok(Some(message)),
+                    Err(StorageError::LogIndexOutofBound) | Err(StorageError::SegmentNotFound) => {
+                        Ok(None)
+                    }
+                    Err(e) => Err(e),
                 }
+            })
+        };
+        let result = Self::wait_for_messages(notifiers, name, request.max_wait_ms, read).await;
+        match result {
+            Ok(Some((id, data))) if visibility_timeout_ms > 0 => {
+                let delivery = Delivery::new(id.to_u64(), data);
+                self.respond_ok(
+                    ResponseMessage::ResponseWithDelivery,
+                    Some(delivery.to_bytes()),
+                )
+                .await;
             }
+            Ok(Some((_, data))) if !data.is_empty() => {
+                self.respond_ok(ResponseMessage::ResponseWithBody, Some(data))
+                    .await;
+            }
+            Err(e) => return self.respond_read_error(name, &group, e).await,
             _ => {
-                error!("NO SUCH COMMAND");
+                self.respond_ok(ResponseMessage::NoNewMessages, None).await;
+            }
+        }
+        info!("INFO: SUBSCRIBED TO TOPIC: {name} GROUP: {group}");
+    }
+    // read up to max_messages for the group in one response, the messages are
+    // acknowledged as soon as they are read
+    async fn subscribe_batch(&mut self, name: &str, group: &str, request: &SubscribeRequest) {
+        let queues = &self.queues;
+        let read = || -> ReadFuture<_> {
+            Box::pin(async move {
+                let Some(queue) = queues.get(name) else {
+                    return Ok(None);
+                };
+                let records = queue
+                    .lock()
+                    .await
+                    .read_group_batch(group, request.max_messages);
+                match records {
+                    Ok(records) => Ok(Some(records)),
+                    Err(StorageError::LogIndexOutofBound) => Ok(None),
+                    Err(e) => Err(e),
+                }
+            })
+        };
+        let result =
+            Self::wait_for_messages(&self.notifiers, name, request.max_wait_ms, read).await;
+        match result {
+            Ok(Some(records)) => {
+                let batch = RecordBatch::new(
+                    records
+                        .into_iter()
+                        .map(|(offset, data)| Record { offset, data })
+                        .collect(),
+                );
+                self.respond_ok(ResponseMessage::ResponseWithBatch, Some(batch.to_bytes()))
+                    .await;
+            }
+            Ok(None) => self.respond_ok(ResponseMessage::NoNewMessages, None).await,
+            Err(e) => self.respond_read_error(name, group, e).await,
+        }
+    }
+    async fn respond_read_error(&mut self, name: &str, group: &str, e: StorageError) {
+        match e {
+            StorageError::CorruptRecord { segment, offset } => {
+                error!("ERROR: CORRUPT RECORD IN TOPIC:{name} SEGMENT:{segment} OFFSET:{offset}");
+                self.respond_err(ResponseMessage::CorruptRecord, None).await;
+            }
+            StorageError::InvalidName => {
+                let body = format!("invalid consumer group: {group}");
+                self.respond_err(ResponseMessage::ErrorResponse, Some(body.into_bytes()))
+                    .await;
+            }
+            _ => self.respond_ok(ResponseMessage::NoNewMessages, None).await,
+        }
+    }
+    // read a batch from the offset, an empty batch holds the request until a message
+    // is published to the queue or the wait time passed
+    async fn fetch(
+        &mut self,
+        name: &str,
+        request: &FetchRequest,
+    ) -> Result<Vec<(u64, Vec<u8>)>, StorageError> {
+        let queues = &self.queues;
+        let read = || -> ReadFuture<_> {
+            Box::pin(async move {
+                match queues.get(name) {
+                    Some(queue) => queue
+                        .lock()
+                        .await
+                        .fetch(request.offset, request.max_messages, request.max_bytes)
+                        .map(|records| (!records.is_empty()).then_some(records)),
+                    None => Ok(None),
+                }
+            })
+        };
+        let records =
+            Self::wait_for_messages(&self.notifiers, name, request.max_wait_ms, read).await?;
+        Ok(records.unwrap_or_default())
+    }
+    // calls read until it finds messages, when it finds none the request is held until a
+    // message is published to the queue or max_wait_ms passed
+    async fn wait_for_messages<'a, T>(
+        notifiers: &Notifiers,
+        name: &str,
+        max_wait_ms: u32,
+        mut read: impl FnMut() -> ReadFuture<'a, T>,
+    ) -> Result<Option<T>, StorageError> {
+        let deadline = Instant::now() + Duration::from_millis(max_wait_ms as u64);
+        let notify = notifiers.queue(name);
+        loop {
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            match read().await? {
+                None if Instant::now() < deadline => {
+                    let _ = time::timeout_at(deadline, notified).await;
+                }
+                messages => return Ok(messages),
+            }
+        }
+    }
+    // hand out the next message of a queue to a consumer group, kept in flight when a
+    // visibility timeout is set. Messages out of delivery attempts go to the dead
+    // letter queue on the way
+    async fn next_message(
+        queues: &Queues,
+        notifiers: &Notifiers,
+        name: &str,
+        group: &str,
+        visibility_timeout_ms: u32,
+    ) -> Result<(RecordId, Vec<u8>), StorageError> {
+        let queue = queues.get(name).ok_or(StorageError::SegmentNotFound)?;
+        let (result, dead_letters) = {
+            let mut commit_log = queue.lock().await;
+            let result = match visibility_timeout_ms {
+                0 => commit_log.read_group_record(group),
+                timeout => commit_log.deliver(group, Duration::from_millis(timeout as u64)),
+            };
+            (result, commit_log.take_dead_letters())
+        };
+        // the queue's lock is released first, a queue is never locked while holding another
+        if !dead_letters.is_empty() {
+            let moved = Self::move_to_dead_letter_queue(queues, name, dead_letters).await;
+            if !moved.is_empty() {
+                notifiers.notify(&dead_letter_queue(name));
+                let mut commit_log = queue.lock().await;
+                for (group, id) in moved {
+                    if let Err(e) = commit_log.ack(&group, id) {
+                        error!(
+                            "ERROR: Failed to remove dead letter {} from {name}: {e}",
+                            id.to_u64()
+                        );
+                    }
+                }
+            }
+        }
+        result
+    }
+    // register the connection as a subscriber of the queue, messages are pushed as
+    // soon as they are appended for as long as the subscriber has credits left
+    async fn stream(&mut self, name: &str, request: StreamRequest) {
+        let group = request.group.unwrap_or_else(|| name.to_owned());
+        if let Some(previous) = self.streams.remove(name) {
+            previous.task.abort();
+        }
+        self.respond_ok(ResponseMessage::EmptyResponse, None).await;
+        let credits = Arc::new(Semaphore::new(request.credits as usize));
+        let task = tokio::spawn(Self::push_messages(
+            self.writer.clone(),
+            self.queues.clone(),
+            self.notifiers.clone(),
+            name.to_owned(),
+            group.clone(),
+            request.visibility_timeout_ms,
+            credits.clone(),
+            self.correlation_id,
+        ));
+        self.streams
+            .insert(name.to_owned(), Subscription { credits, task });
+        info!("INFO: STREAMING TOPIC: {name} TO GROUP: {group}");
+    }
+    // pushes a message for every credit, waiting for appends when the subscriber
+    // caught up. In flight messages are checked again once their timeout passed
+    #[allow(clippy::too_many_arguments)]
+    async fn push_messages(
+        writer: ResponseWriter,
+        queues: Arc<Queues>,
+        notifiers: Arc<Notifiers>,
+        name: String,
+        group: String,
+        visibility_timeout_ms: u32,
+        credits: Arc<Semaphore>,
+        // pushed messages carry the correlation id of the STREAM request
+        correlation_id: Option<u32>,
+    ) {
+        let notify = notifiers.queue(&name);
+        loop {
+            match credits.acquire().await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return,
+            }
+            let resp = loop {
+                let notified = notify.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                match Self::next_message(&queues, &notifiers, &name, &group, visibility_timeout_ms)
+                    .await
+                {
+                    Ok((id, data)) => {
+                        let delivery = Delivery::new(id.to_u64(), data);
+                        break Response::new(
+                            ResponseCode::Ok,
+                            ResponseMessage::ResponseWithDelivery,
+                            Some(delivery.to_bytes()),
+                        );
+                    }
+                    Err(StorageError::LogIndexOutofBound) | Err(StorageError::SegmentNotFound) => {
+                        match visibility_timeout_ms {
+                            0 => notified.await,
+                            timeout => {
+                                let timeout = Duration::from_millis(timeout as u64);
+                                let _ = tokio::time::timeout(timeout, notified).await;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("ERROR: Stopped streaming {name} to {group}: {e}");
+                        let body = format!("stream of {name} stopped: {e}");
+                        let resp = Response::new(
+                            ResponseCode::Err,
+                            ResponseMessage::ErrorResponse,
+                            Some(body.into_bytes()),
+                        );
+                        let _ = send_response(&writer, correlate(resp, correlation_id)).await;
+                        return;
+                    }
+                }
+            };
+            if let Err(e) = send_response(&writer, correlate(resp, correlation_id)).await {
+                error!("ERROR: Failed to push message to stream: {:?}", e);
+                return;
             }
         }
     }
-    async fn save_to_queue(&mut self, queue: &str, data: &[u8]) {
-        let mut messages = self.messages.write().await;
-        if let Some(topic) = messages.get_mut(queue) {
-            topic.save_to_disk(data).unwrap();
+    // append messages that ran out of delivery attempts to the queue's dead letter queue,
+    // returns the ones that were written so they can be acknowledged in the queue
+    async fn move_to_dead_letter_queue(
+        queues: &Queues,
+        name: &str,
+        dead_letters: Vec<DeadLetter>,
+    ) -> Vec<(String, RecordId)> {
+        let dlq_name = dead_letter_queue(name);
+        // the messages stay in flight in the queue when there is no dead letter queue
+        let dlq = match queues.get_or_create(&dlq_name) {
+            Ok(dlq) => dlq,
+            Err(e) => {
+                error!("ERROR: Failed to create {dlq_name}: {e}");
+                return Vec::new();
+            }
+        };
+        let mut dlq = dlq.lock().await;
+        let mut moved = Vec::new();
+        for dead_letter in dead_letters {
+            let message = DeadLetterMessage {
+                queue: name.to_owned(),
+                group: dead_letter.group,
+                offset: dead_letter.id.to_u64(),
+                deliveries: dead_letter.deliveries,
+                message: dead_letter.message,
+            };
+            match dlq.save_to_disk(&message.to_bytes()) {
+                Ok(_) => {
+                    info!(
+                        "INFO: MOVED MESSAGE {} OF TOPIC:{name} TO {dlq_name}",
+                        message.offset
+                    );
+                    moved.push((message.group, dead_letter.id));
+                }
+                Err(e) => error!("ERROR: Failed to move message to {dlq_name}: {e}"),
+            }
         }
+        moved
+    }
+    // publish every message of the queue's dead letter queue back to the queue, a
+    // message is only acknowledged in the dead letter queue once it was republished
+    async fn replay_dead_letters(&mut self, name: &str) -> Result<u32, StorageError> {
+        let dlq_name = dead_letter_queue(name);
+        let Some(dlq) = self.queues.get(&dlq_name) else {
+            return Ok(0);
+        };
+        let mut replayed = 0;
+        loop {
+            // only one queue is locked at a time
+            let delivery = dlq
+                .lock()
+                .await
+                .deliver(REPLAY_GROUP, Duration::from_secs(60));
+            let (id, data) = match delivery {
+                Ok(delivery) => delivery,
+                Err(StorageError::LogIndexOutofBound) => return Ok(replayed),
+                Err(e) => return Err(e),
+            };
+            match DeadLetterMessage::from_bytes(&data) {
+                Some(letter) => {
+                    let queue = self
+                        .queues
+                        .get(&letter.queue)
+                        .ok_or(StorageError::SegmentNotFound)?;
+                    queue.lock().await.save_to_disk(&letter.message)?;
+                    replayed += 1;
+                }
+                None => error!(
+                    "ERROR: Skipping malformed dead letter {} in {dlq_name}",
+                    id.to_u64()
+                ),
+            }
+            dlq.lock().await.ack(REPLAY_GROUP, id)?;
+        }
+    }
+    async fn respond_ok(&mut self, message: ResponseMessage, data: Option<Vec<u8>>) {
+        let resp = correlate(
+            Response::new(ResponseCode::Ok, message, data),
+            self.correlation_id,
+        );
+        if let Err(e) = send_response(&self.writer, resp).await {
+            error!("ERROR: Failed to write response to stream: {:?}", e);
+        }
+    }
+    async fn respond_err(&mut self, message: ResponseMessage, data: Option<Vec<u8>>) {
+        let resp = correlate(
+            Response::new(ResponseCode::Err, message, data),
+            self.correlation_id,
+        );
+        if let Err(e) = send_response(&self.writer, resp).await {
+            error!("ERROR: Failed to write response to stream: {:?}", e);
+        }
+    }
+    // append to a queue and wake up the requests waiting on it
+    async fn save_to_queue(&mut self, queue: &str, data: &[u8]) -> Result<u64, StorageError> {
+        let offset = match self.queues.get(queue) {
+            Some(topic) => topic.lock().await.save_to_disk(data)?,
+            None => return Err(StorageError::SegmentNotFound),
+        };
+        self.notifiers.notify(queue);
+        Ok(offset)
+    }
+    // append a batch to a queue as one write, creating the queue on first use
+    async fn save_batch_to_queue(
+        &mut self,
+        queue: &str,
+        records: &[&[u8]],
+    ) -> Result<Vec<u64>, StorageError> {
+        if !valid_name(queue) {
+            return Err(StorageError::InvalidName);
+        }
+        let offsets = self
+            .queues
+            .get_or_create(queue)?
+            .lock()
+            .await
+            .save_batch(records)?;
+        self.notifiers.notify(queue);
+        Ok(offsets)
     }
     async fn create_new_queue(
         &mut self,
         queue_name: &str,
         payload: &[u8],
-    ) -> Result<(), ServerError> {
-        let mut log = CommitLog::new(queue_name, SEGMENT_SIZE as u64, DIR_PATH);
-        log.save_to_disk(payload).unwrap();
-        self.messages
-            .write()
+    ) -> Result<u64, StorageError> {
+        if !valid_name(queue_name) {
+            return Err(StorageError::InvalidName);
+        }
+        let offset = self
+            .queues
+            .get_or_create(queue_name)?
+            .lock()
             .await
-            .insert(queue_name.to_owned(), log);
-        Ok(())
+            .save_to_disk(payload)?;
+        self.notifiers.notify(queue_name);
+        Ok(offset)
     }
-    pub async fn restore_from_disk(mut messages: Arc<RwLock<HashMap<String, CommitLog>>>) {
-        match CommitLog::restore_from_disk(SEGMENT_SIZE as u64, DIR_PATH) {
+    /// load the queues stored in the data directory, an empty or missing directory
+    /// has none. Any other error means queues can't be loaded and the broker must not
+    /// start without them
+    pub async fn restore_from_disk(queues: Arc<Queues>) -> Result<(), StorageError> {
+        match CommitLog::restore_from_disk(queues.config, &queues.dir_path) {
             Ok(logs) => {
                 for log in logs {
-                    let mut messages = messages.borrow_mut().write().await;
                     info!("loading topic  name:{}, path:{:?}", &log.name, log.dir_path);
-                    messages.insert(log.name.to_owned(), log);
+                    queues.insert(log);
                 }
+                Ok(())
             }
-            Err(e) => {
-                if e.to_string() == StorageError::IoError(io::Error::last_os_error()).to_string() {
-                    info!("No topic created: {e:?}");
-                } else {
-                    error!("an error occurred:{e}")
+            Err(StorageError::DirEmpty) => Ok(()),
+            Err(StorageError::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No topic created: {e:?}");
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+    /// delete the segments of every queue that fall outside its retention policy
+    pub async fn apply_retention(queues: Arc<Queues>) {
+        for (name, queue) in queues.all() {
+            let mut log = queue.lock().await;
+            match log.apply_retention() {
+                Ok(0) => {}
+                Ok(deleted) => {
+                    info!("INFO: RETENTION DELETED {deleted} SEGMENTS FROM TOPIC:{name}")
                 }
+                Err(e) => error!("ERROR: Failed to apply retention to {name}: {e}"),
+            }
+        }
+    }
+    /// sync the queues whose durability interval passed
+    pub async fn sync_queues(queues: Arc<Queues>) {
+        for (name, queue) in queues.all() {
+            let mut log = queue.lock().await;
+            if let Err(e) = log.sync_if_due() {
+                error!("ERROR: Failed to sync {name}: {e}");
+            }
+        }
+    }
+    /// flush and fsync every queue and mark it as cleanly shut down, called once no
+    /// more requests are handled
+    pub async fn shutdown_queues(queues: Arc<Queues>) {
+        for (name, queue) in queues.all() {
+            if let Err(e) = queue.lock().await.shutdown() {
+                error!("ERROR: Failed to shut down {name}: {e}");
+            }
+        }
+    }
+    /// compact every queue that has compaction enabled down to the latest topic per key
+    pub async fn compact_queues(queues: Arc<Queues>) {
+        for (name, queue) in queues.all() {
+            let mut log = queue.lock().await;
+            match log.compact(topic_key) {
+                Ok(0) => {}
+                Ok(removed) => {
+                    info!("INFO: COMPACTION REMOVED {removed} RECORDS FROM TOPIC:{name}")
+                }
+                Err(e) => error!("ERROR: Failed to compact {name}: {e}"),
             }
         }
     }
 }
 
+/// a connection to a server, requests can be sent concurrently from many tasks sharing
+/// the client and every response is routed back to the request it answers
 pub struct MessageQueueClient {
-    stream: TcpStream,
+    writer: Mutex<FramedWrite<OwnedWriteHalf, ClientCodec>>,
+    routes: Arc<std::sync::Mutex<Routes>>,
+    next_correlation_id: AtomicU32,
+    // messages pushed by streamed queues, read with `next_delivery`
+    pushed: Mutex<mpsc::UnboundedReceiver<Response>>,
+    // reads the responses of the connection and routes them
+    reader: JoinHandle<()>,
+    // what the server agreed on when the client connected
+    hello: Hello,
+}
+
+// where the responses read by a client go
+#[derive(Debug, Default)]
+struct Routes {
+    // requests waiting for their response in the order they were sent. Responses are
+    // matched by correlation id, servers older than protocol version 3 don't send one
+    // and answer in order
+    pending: VecDeque<(u32, oneshot::Sender<Response>)>,
+    // the correlation ids of STREAM requests by queue, their pushed messages echo them.
+    // Streaming a queue again replaces the stream before like on the server
+    streams: HashMap<String, u32>,
+    closed: bool,
+}
+
+impl Drop for MessageQueueClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+// the error an error response stands for, a missing queue is NotFound
+fn error_response(resp: Response) -> io::Error {
+    let body = String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+    match resp.response_message == ResponseMessage::QueueNotFound as u16 {
+        true => io::Error::new(ErrorKind::NotFound, body),
+        false => io::Error::other(body),
+    }
 }
 
 impl MessageQueueClient {
+    /// connect to a server and agree on the protocol version and features to use
     pub async fn dial(server_address: &str) -> Result<MessageQueueClient, io::Error> {
-        let stream = TcpStream::connect(server_address).await.unwrap();
-        Ok(Self { stream })
+        let (reader, writer) = TcpStream::connect(server_address).await?.into_split();
+        let routes = Arc::new(std::sync::Mutex::new(Routes::default()));
+        let (pushed_tx, pushed) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(Self::route_responses(
+            FramedRead::new(reader, ClientCodec),
+            routes.clone(),
+            pushed_tx,
+        ));
+        let mut client = Self {
+            writer: Mutex::new(FramedWrite::new(writer, ClientCodec)),
+            routes,
+            next_correlation_id: AtomicU32::new(1),
+            pushed: Mutex::new(pushed),
+            reader,
+            hello: Hello::new(PROTOCOL_VERSION, SUPPORTED_FEATURES),
+        };
+        client.hello = client.handshake().await?;
+        Ok(client)
+    }
+
+    // hand every response to the request it answers, messages pushed by streams go to
+    // `next_delivery`
+    async fn route_responses(
+        mut responses: FramedRead<OwnedReadHalf, ClientCodec>,
+        routes: Arc<std::sync::Mutex<Routes>>,
+        pushed: mpsc::UnboundedSender<Response>,
+    ) {
+        while let Some(resp) = responses.next().await {
+            let resp = match resp {
+                Ok(resp) => resp,
+                Err(e) => {
+                    error!("ERROR: Failed to read response: {e}");
+                    break;
+                }
+            };
+            let mut routes = routes.lock().unwrap_or_else(PoisonError::into_inner);
+            let request = match resp.correlation_id {
+                Some(id) => routes
+                    .pending
+                    .iter()
+                    .position(|(pending, _)| *pending == id),
+                None if !routes.streams.is_empty()
+                    && resp.response_message == ResponseMessage::ResponseWithDelivery as u16 =>
+                {
+                    None
+                }
+                None => (!routes.pending.is_empty()).then_some(0),
+            };
+            match request.and_then(|request| routes.pending.remove(request)) {
+                Some((_, request)) => {
+                    let _ = request.send(resp);
+                }
+                None if resp
+                    .correlation_id
+                    .is_none_or(|id| routes.streams.values().any(|stream| *stream == id)) =>
+                {
+                    let _ = pushed.send(resp);
+                }
+                None => error!("ERROR: Dropped a response to an unknown request"),
+            }
+        }
+        // requests still waiting see the connection closed
+        let mut routes = routes.lock().unwrap_or_else(PoisonError::into_inner);
+        routes.pending.clear();
+        routes.closed = true;
     }
 
-    pub async fn publish(&mut self, queue_name: &str, message: &[u8]) -> Result<(), io::Error> {
+    async fn handshake(&self) -> Result<Hello, io::Error> {
+        // sent in the v0 layout every server version decodes
+        let payload = BinaryHeader::new(13, None, Some(self.hello.to_bytes()))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_message == ResponseMessage::UnsupportedVersion as u16 => {
+                let versions = resp.response_data.unwrap_or_default();
+                let body = match versions.get(..4) {
+                    Some(v) => format!(
+                        "server speaks protocol versions {} to {}, not {PROTOCOL_VERSION}",
+                        u16::from_be_bytes([v[0], v[1]]),
+                        u16::from_be_bytes([v[2], v[3]])
+                    ),
+                    None => "unsupported protocol version".to_string(),
+                };
+                Err(io::Error::new(ErrorKind::Unsupported, body))
+            }
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(resp) => match Hello::from_bytes(&resp.response_data.unwrap_or_default()) {
+                // a server older than the client answers with a version it may no longer speak
+                Some(hello) if hello.version < MIN_PROTOCOL_VERSION => Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    format!("server speaks protocol version {}", hello.version),
+                )),
+                Some(hello) => Ok(hello),
+                None => Err(io::Error::new(ErrorKind::InvalidData, "malformed hello")),
+            },
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// the protocol version agreed on with the server
+    pub fn protocol_version(&self) -> u16 {
+        self.hello.version
+    }
+
+    /// whether the server agreed to use a FEATURE_* feature
+    pub fn supports(&self, feature: u32) -> bool {
+        self.hello.supports(feature)
+    }
+
+    // a request in the frame layout agreed on with the server
+    fn header(
+        &self,
+        command: u32,
+        queue_name: Option<String>,
+        payload: Option<Vec<u8>>,
+    ) -> Result<BinaryHeader, io::Error> {
+        let header = BinaryHeader::new(command, queue_name, payload)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        Ok(header.with_format(self.hello.frame_format()))
+    }
+
+    fn require_feature(&self, feature: u32, name: &str) -> Result<(), io::Error> {
+        match self.supports(feature) {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("the server doesn't support {name}"),
+            )),
+        }
+    }
+
+    /// publish a message, returns once the server acknowledged it which happens after
+    /// the message is as durable as the queue's durability mode asks for
+    pub async fn publish(&self, queue_name: &str, message: &[u8]) -> Result<(), io::Error> {
         let topic = Topic::new(1, 1718709072, message.to_vec());
-        let payload = BinaryHeader::new(2, Some(queue_name.to_string()), Some(topic.to_bytes()));
-        self.send_message(payload.to_bytes()).await?;
-        info!("Payload sent: {payload:?}");
-        Ok(())
+        self.publish_topic(queue_name, topic).await
     }
 
-    pub async fn subscribe(&mut self, queue_name: &str) -> Result<(), std::io::Error> {
-        let (mut rx, mut tx) = self.stream.split();
-        let payload = BinaryHeader::new(1, Some(queue_name.to_string()), None);
-        match tx.write(&payload.to_bytes()).await {
-            Ok(_) => (),
-            Err(e) if e.kind() == ErrorKind::BrokenPipe => {
-                error!("Connection closed by server: {:?}", e);
+    /// publish a keyed message, an empty message is a tombstone deleting the key
+    /// from compacted queues
+    pub async fn publish_with_key(
+        &self,
+        queue_name: &str,
+        key: &[u8],
+        message: &[u8],
+    ) -> Result<(), io::Error> {
+        let topic = Topic::with_key(1, 1718709072, key.to_vec(), message.to_vec());
+        self.publish_topic(queue_name, topic).await
+    }
+
+    /// publish topics as one write acknowledged once, returns the offsets they got
+    pub async fn publish_batch(
+        &self,
+        queue_name: &str,
+        messages: &[&[u8]],
+    ) -> Result<Vec<u64>, io::Error> {
+        self.require_feature(FEATURE_BATCHING, "batching")?;
+        let topics = messages
+            .iter()
+            .map(|message| Topic::new(1, 1718709072, message.to_vec()))
+            .collect();
+        let batch = PublishBatch::new(topics);
+        let payload = self.header(12, Some(queue_name.to_string()), Some(batch.to_bytes()))?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
             }
-            Err(e) => return Err(e),
+            Some(resp) => decode_offsets(&resp.response_data.unwrap_or_default())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed offsets")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
         }
-        let mut buffer = vec![0; 1024];
-        match rx.read(&mut buffer).await {
-            Ok(bytes_read) if bytes_read > 0 => {
-                let resp = Response::from_bytes(&buffer[..bytes_read]);
-                info!("{:?}", resp);
+    }
+
+    /// read up to `max_messages` messages of a queue for a consumer group in one
+    /// response, waiting up to `max_wait` when there are none. They are acknowledged
+    /// as soon as they are read
+    pub async fn poll_batch(
+        &self,
+        queue_name: &str,
+        group: Option<&str>,
+        max_messages: u32,
+        max_wait: Duration,
+    ) -> Result<Vec<Record>, std::io::Error> {
+        self.require_feature(FEATURE_BATCHING, "batching")?;
+        let request = SubscribeRequest {
+            group: group.map(|g| g.to_string()),
+            max_wait_ms: max_wait.as_millis() as u32,
+            max_messages: max_messages.max(1),
+            ..Default::default()
+        };
+        let payload = self.header(1, Some(queue_name.to_string()), Some(request.to_bytes()))?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(resp) if resp.response_message == ResponseMessage::ResponseWithBatch as u16 => {
+                RecordBatch::from_bytes(&resp.response_data.unwrap_or_default())
+                    .map(|batch| batch.records)
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed record batch"))
             }
-            Ok(_) => {}
-            Err(e) if e.kind() == ErrorKind::BrokenPipe => {
-                error!("Connection closed by server: {:?}", e);
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    async fn publish_topic(&self, queue_name: &str, topic: Topic) -> Result<(), io::Error> {
+        let payload = self.header(2, Some(queue_name.to_string()), Some(topic.to_bytes()))?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
             }
-            Err(e) => return Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub async fn subscribe(&self, queue_name: &str) -> Result<(), std::io::Error> {
+        self.subscribe_group(queue_name, None).await
+    }
+
+    /// read the next message of a queue for a consumer group, every group receives
+    /// all messages independently of the others
+    pub async fn subscribe_group(
+        &self,
+        queue_name: &str,
+        group: Option<&str>,
+    ) -> Result<(), std::io::Error> {
+        let request = SubscribeRequest::new(group.map(|g| g.to_string()));
+        let payload = self.header(1, Some(queue_name.to_string()), Some(request.to_bytes()))?;
+        if let Some(resp) = self.request(payload).await? {
+            info!("{:?}", resp);
         }
         Ok(())
     }
 
-    async fn send_message(&mut self, payload: Vec<u8>) -> Result<(), std::io::Error> {
-        match self.stream.write(&payload).await {
-            Ok(_n) => Ok(()),
-            Err(err) => {
-                if err.kind() == std::io::ErrorKind::WouldBlock {
-                    Err(err)
-                } else {
-                    error!("ERROR: Failed to write to stream: {:?}", err);
-                    Err(err)
+    /// read the next message of a queue for a consumer group, waiting up to `max_wait`
+    /// for one to be published when the group already read everything
+    pub async fn poll(
+        &self,
+        queue_name: &str,
+        group: Option<&str>,
+        max_wait: Duration,
+    ) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let request = SubscribeRequest {
+            group: group.map(|g| g.to_string()),
+            max_wait_ms: max_wait.as_millis() as u32,
+            ..Default::default()
+        };
+        let payload = self.header(1, Some(queue_name.to_string()), Some(request.to_bytes()))?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(resp) if resp.response_message == ResponseMessage::ResponseWithBody as u16 => {
+                Ok(resp.response_data)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// take the next message of a queue for a consumer group, the message is handed
+    /// out again unless it is acknowledged with `ack` within the visibility timeout
+    pub async fn subscribe_with_ack(
+        &self,
+        queue_name: &str,
+        group: Option<&str>,
+        visibility_timeout: Duration,
+    ) -> Result<Option<Delivery>, std::io::Error> {
+        let request = SubscribeRequest {
+            group: group.map(|g| g.to_string()),
+            visibility_timeout_ms: visibility_timeout.as_millis().max(1) as u32,
+            ..Default::default()
+        };
+        let payload = self.header(1, Some(queue_name.to_string()), Some(request.to_bytes()))?;
+        let delivery = self.request(payload).await?.and_then(|resp| {
+            if resp.response_message != ResponseMessage::ResponseWithDelivery as u16 {
+                return None;
+            }
+            Delivery::from_bytes(&resp.response_data.unwrap_or_default())
+        });
+        Ok(delivery)
+    }
+
+    /// acknowledge a message taken with `subscribe_with_ack`
+    pub async fn ack(
+        &self,
+        queue_name: &str,
+        group: Option<&str>,
+        id: u64,
+    ) -> Result<(), std::io::Error> {
+        self.acknowledge(5, queue_name, group, id).await
+    }
+
+    /// reject a message taken with `subscribe_with_ack` so it is handed out again
+    pub async fn nack(
+        &self,
+        queue_name: &str,
+        group: Option<&str>,
+        id: u64,
+    ) -> Result<(), std::io::Error> {
+        self.acknowledge(6, queue_name, group, id).await
+    }
+
+    /// publish the messages of the queue's dead letter queue back to the queue,
+    /// returns how many messages were replayed
+    pub async fn replay_dead_letters(&self, queue_name: &str) -> Result<u32, std::io::Error> {
+        let payload = self.header(7, Some(queue_name.to_string()), None)?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                Err(error_response(resp))
+            }
+            Some(resp) => {
+                let data = resp.response_data.unwrap_or_default();
+                Ok(data
+                    .get(..4)
+                    .map(|count| u32::from_be_bytes(count.try_into().unwrap()))
+                    .unwrap_or(0))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// read up to `max_messages` messages starting at `offset` without moving any
+    /// consumer group, used to replay a queue from any point
+    pub async fn fetch(
+        &self,
+        queue_name: &str,
+        offset: u64,
+        max_messages: u32,
+        max_bytes: u32,
+    ) -> Result<Vec<Record>, std::io::Error> {
+        self.fetch_wait(queue_name, offset, max_messages, max_bytes, Duration::ZERO)
+            .await
+    }
+
+    /// like `fetch` but the server holds the request for up to `max_wait` until there
+    /// are messages at the offset
+    pub async fn fetch_wait(
+        &self,
+        queue_name: &str,
+        offset: u64,
+        max_messages: u32,
+        max_bytes: u32,
+        max_wait: Duration,
+    ) -> Result<Vec<Record>, std::io::Error> {
+        let request = FetchRequest {
+            max_wait_ms: max_wait.as_millis() as u32,
+            ..FetchRequest::new(offset, max_messages, max_bytes)
+        };
+        let payload = self.header(8, Some(queue_name.to_string()), Some(request.to_bytes()))?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(resp) => RecordBatch::from_bytes(&resp.response_data.unwrap_or_default())
+                .map(|batch| batch.records)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed record batch")),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// the offset of the first message published at or after `time`, fetching from it
+    /// replays everything since then
+    pub async fn offset_for_time(
+        &self,
+        queue_name: &str,
+        time: SystemTime,
+    ) -> Result<u64, std::io::Error> {
+        let timestamp = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        let payload = self.header(
+            9,
+            Some(queue_name.to_string()),
+            Some(timestamp.to_be_bytes().to_vec()),
+        )?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                Err(error_response(resp))
+            }
+            Some(resp) => resp
+                .response_data
+                .as_deref()
+                .and_then(|data| data.get(..8))
+                .map(|offset| u64::from_be_bytes(offset.try_into().unwrap()))
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "missing offset")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// say goodbye and close the connection, returns once the server answered
+    pub async fn quit(self) -> Result<(), std::io::Error> {
+        let payload = self.header(0, None, None)?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_message == ResponseMessage::Goodbye as u16 => Ok(()),
+            Some(_) => Err(io::Error::new(ErrorKind::InvalidData, "expected a goodbye")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// change the settings of a queue, returns the settings it runs with afterwards
+    pub async fn alter_queue(
+        &self,
+        queue_name: &str,
+        request: AlterQueueRequest,
+    ) -> Result<QueueSettings, std::io::Error> {
+        let payload = self.header(14, Some(queue_name.to_string()), Some(request.to_bytes()))?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                Err(error_response(resp))
+            }
+            Some(resp) => QueueSettings::from_bytes(&resp.response_data.unwrap_or_default())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed settings")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// create a queue, settings left out of the request are the broker's defaults
+    pub async fn create_queue(
+        &self,
+        queue_name: &str,
+        request: AlterQueueRequest,
+    ) -> Result<QueueSettings, std::io::Error> {
+        let payload = self.header(15, Some(queue_name.to_string()), Some(request.to_bytes()))?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(resp) => QueueSettings::from_bytes(&resp.response_data.unwrap_or_default())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed settings")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// delete a queue with all its messages and consumer offsets
+    pub async fn delete_queue(&self, queue_name: &str) -> Result<(), std::io::Error> {
+        let payload = self.header(16, Some(queue_name.to_string()), None)?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                Err(error_response(resp))
+            }
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// drop every message of a queue, its consumer groups continue with the messages
+    /// published afterwards. Returns the number of messages removed
+    pub async fn purge_queue(&self, queue_name: &str) -> Result<u64, std::io::Error> {
+        let payload = self.header(19, Some(queue_name.to_string()), None)?;
+        self.truncate_request(payload).await
+    }
+
+    /// drop the messages of a queue before the offset, returns the number removed
+    pub async fn truncate_queue(
+        &self,
+        queue_name: &str,
+        offset: u64,
+    ) -> Result<u64, std::io::Error> {
+        let payload = self.header(
+            20,
+            Some(queue_name.to_string()),
+            Some(offset.to_be_bytes().to_vec()),
+        )?;
+        self.truncate_request(payload).await
+    }
+
+    async fn truncate_request(&self, payload: BinaryHeader) -> Result<u64, std::io::Error> {
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                Err(error_response(resp))
+            }
+            Some(resp) => resp
+                .response_data
+                .as_deref()
+                .and_then(|data| data.get(..8))
+                .map(|data| u64::from_be_bytes(data.try_into().unwrap()))
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed count")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// the names of the broker's queues, sorted
+    pub async fn list_queues(&self) -> Result<Vec<String>, std::io::Error> {
+        let payload = self.header(17, None, None)?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(resp) => decode_queue_names(&resp.response_data.unwrap_or_default())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed queue names")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// the settings and stats of a queue
+    pub async fn describe_queue(
+        &self,
+        queue_name: &str,
+    ) -> Result<QueueDescription, std::io::Error> {
+        let payload = self.header(18, Some(queue_name.to_string()), None)?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                Err(error_response(resp))
+            }
+            Some(resp) => QueueDescription::from_bytes(&resp.response_data.unwrap_or_default())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed description")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// check the server is alive, the pong carries its clock and version
+    pub async fn ping(&self) -> Result<Pong, std::io::Error> {
+        let payload = self.header(3, None, None)?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(resp) => Pong::from_bytes(&resp.response_data.unwrap_or_default())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed pong")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// storage statistics and consumer lag of a queue, or of every queue when no name
+    /// is given
+    pub async fn stats(&self, queue_name: Option<&str>) -> Result<Stats, std::io::Error> {
+        let payload = self.header(4, queue_name.map(str::to_string), None)?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                Err(error_response(resp))
+            }
+            Some(resp) => Stats::from_bytes(&resp.response_data.unwrap_or_default())
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed stats")),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// subscribe to a queue in streaming mode, the server pushes messages as soon as
+    /// they are published while credits are left, read them with `next_delivery`.
+    /// Other requests that answer with a delivery, like `subscribe_with_ack`, can't be
+    /// told apart from pushed messages once a connection streams
+    pub async fn stream(
+        &self,
+        queue_name: &str,
+        group: Option<&str>,
+        visibility_timeout: Duration,
+        credits: u32,
+    ) -> Result<(), std::io::Error> {
+        let request = StreamRequest::new(
+            group.map(|g| g.to_string()),
+            visibility_timeout.as_millis() as u32,
+            credits,
+        );
+        let payload = self.header(10, Some(queue_name.to_string()), Some(request.to_bytes()))?;
+        match self.send(payload, true).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(_) => Ok(()),
+            None => Err(io::Error::new(ErrorKind::UnexpectedEof, "no response")),
+        }
+    }
+
+    /// allow the server to push `credits` more messages of a streamed queue
+    pub async fn credit(&self, queue_name: &str, credits: u32) -> Result<(), std::io::Error> {
+        let payload = self.header(
+            11,
+            Some(queue_name.to_string()),
+            Some(credits.to_be_bytes().to_vec()),
+        )?;
+        let mut writer = self.writer.lock().await;
+        writer.send(payload).await.map_err(io::Error::from)
+    }
+
+    /// wait for the next message pushed by a stream, None once the connection closed
+    pub async fn next_delivery(&self) -> Result<Option<Delivery>, std::io::Error> {
+        let resp = self.pushed.lock().await.recv().await;
+        match resp {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                let body =
+                    String::from_utf8_lossy(&resp.response_data.unwrap_or_default()).to_string();
+                Err(io::Error::other(body))
+            }
+            Some(resp) => Delivery::from_bytes(&resp.response_data.unwrap_or_default())
+                .map(Some)
+                .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "malformed delivery")),
+            None => Ok(None),
+        }
+    }
+
+    async fn acknowledge(
+        &self,
+        command: u32,
+        queue_name: &str,
+        group: Option<&str>,
+        id: u64,
+    ) -> Result<(), std::io::Error> {
+        let request = AckRequest::new(group.map(|g| g.to_string()), id);
+        let payload = self.header(
+            command,
+            Some(queue_name.to_string()),
+            Some(request.to_bytes()),
+        )?;
+        match self.request(payload).await? {
+            Some(resp) if resp.response_code == ResponseCode::Err as u16 => {
+                Err(error_response(resp))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // send a request and wait for the server's response, None once the connection closed
+    async fn request(&self, payload: BinaryHeader) -> Result<Option<Response>, io::Error> {
+        self.send(payload, false).await
+    }
+
+    // send a request, the messages pushed in response to a STREAM request are routed
+    // to `next_delivery` once it was answered
+    async fn send(
+        &self,
+        payload: BinaryHeader,
+        stream: bool,
+    ) -> Result<Option<Response>, io::Error> {
+        let correlation_id = self.next_correlation_id.fetch_add(1, Ordering::Relaxed);
+        let payload = match payload.format {
+            FrameFormat::V2 => payload.with_correlation_id(correlation_id),
+            _ => payload,
+        };
+        let stream = stream.then(|| payload.queue_name.clone().unwrap_or_default());
+        let (sender, response) = oneshot::channel();
+        {
+            // requests are registered in the order they are written for servers that
+            // answer in order
+            let mut writer = self.writer.lock().await;
+            {
+                let mut routes = self.routes.lock().unwrap_or_else(PoisonError::into_inner);
+                if routes.closed {
+                    return Ok(None);
+                }
+                routes.pending.push_back((correlation_id, sender));
+                if let Some(queue) = &stream {
+                    routes.streams.insert(queue.clone(), correlation_id);
+                }
+            }
+            if let Err(e) = writer.send(payload).await.map_err(io::Error::from) {
+                // the request never reached the server, nothing will answer it
+                self.forget_request(correlation_id, stream.as_deref());
+                if e.kind() != ErrorKind::BrokenPipe {
+                    return Err(e);
                 }
+                error!("ERROR: Connection closed by server: {e}");
+                return Ok(None);
+            }
+        }
+        let response = response.await.ok();
+        // a stream the server refused pushes nothing
+        if let Some(queue) = &stream {
+            if response
+                .as_ref()
+                .is_none_or(|resp| resp.response_code == ResponseCode::Err as u16)
+            {
+                self.forget_request(correlation_id, Some(queue));
+            }
+        }
+        Ok(response)
+    }
+    // drop the routes of a request that won't get a response
+    fn forget_request(&self, correlation_id: u32, stream: Option<&str>) {
+        let mut routes = self.routes.lock().unwrap_or_else(PoisonError::into_inner);
+        routes
+            .pending
+            .retain(|(pending, _)| *pending != correlation_id);
+        if let Some(queue) = stream {
+            if routes.streams.get(queue) == Some(&correlation_id) {
+                routes.streams.remove(queue);
             }
         }
     }
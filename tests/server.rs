@@ -0,0 +1,377 @@
+use futures::{SinkExt, StreamExt};
+use mq::internal::log::LogConfig;
+use mq::{
+    AlterQueueRequest, BinaryHeader, ClientCodec, Hello, MessageQueueClient, Notifiers, Queues,
+    Record, Response, ResponseCode, ResponseMessage, Server, Topic,
+};
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time;
+use tokio_util::codec::Framed;
+
+const PATH: &str = "test_data/server";
+
+// a broker on a free local port keeping its queues in a fresh directory, it serves
+// connections until the test's runtime shuts down
+async fn start_server(name: &str) -> String {
+    let path = format!("{PATH}/{name}/");
+    let _ = fs::remove_dir_all(&path);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let queues = Arc::new(Queues::new(LogConfig::new(1024 * 1024), &path));
+    let notifiers = Arc::new(Notifiers::default());
+    tokio::spawn(async move {
+        // connections close once the sender is dropped
+        let (_shutdown, shutdown_requested) = watch::channel(false);
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(Server::handle_connection(
+                stream,
+                queues.clone(),
+                notifiers.clone(),
+                shutdown_requested.clone(),
+            ));
+        }
+    });
+    addr
+}
+
+// a connection writing its own frames, for requests the client never sends
+async fn dial_raw(addr: &str) -> Framed<TcpStream, ClientCodec> {
+    Framed::new(TcpStream::connect(addr).await.unwrap(), ClientCodec)
+}
+
+// send a v0 frame and wait for its response
+async fn roundtrip(
+    conn: &mut Framed<TcpStream, ClientCodec>,
+    command: u32,
+    queue_name: Option<&str>,
+    payload: Option<Vec<u8>>,
+) -> Response {
+    let frame = BinaryHeader::new(command, queue_name.map(str::to_string), payload).unwrap();
+    conn.send(frame).await.unwrap();
+    time::timeout(Duration::from_secs(5), conn.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+}
+
+// the message a record was published with
+fn message(record: &Record) -> Vec<u8> {
+    Topic::from_bytes(&record.data).unwrap().message
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_concurrent_publish_and_fetch() {
+    let addr = start_server("concurrent").await;
+    let client = Arc::new(MessageQueueClient::dial(&addr).await.unwrap());
+    let mut publishes = JoinSet::new();
+    for n in 0..20 {
+        let client = client.clone();
+        publishes.spawn(async move { client.publish("jobs", format!("job-{n}").as_bytes()).await });
+    }
+    while let Some(published) = publishes.join_next().await {
+        published.unwrap().unwrap();
+    }
+
+    // fetches sent at once on one connection each get the answer to their own offset
+    let mut fetches = JoinSet::new();
+    for offset in 0..20 {
+        let client = client.clone();
+        fetches.spawn(async move { (offset, client.fetch("jobs", offset, 1, 1024).await) });
+    }
+    let mut messages = HashSet::new();
+    while let Some(fetched) = fetches.join_next().await {
+        let (offset, records) = fetched.unwrap();
+        let records = records.unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].offset, offset);
+        messages.insert(message(&records[0]));
+    }
+    assert_eq!(messages.len(), 20);
+}
+
+#[tokio::test]
+async fn test_long_poll_answered_out_of_order() {
+    let addr = start_server("out_of_order").await;
+    let client = Arc::new(MessageQueueClient::dial(&addr).await.unwrap());
+    client.publish("events", b"first").await.unwrap();
+    let waiting = tokio::spawn({
+        let client = client.clone();
+        async move {
+            client
+                .fetch_wait("events", 1, 10, 1024, Duration::from_secs(10))
+                .await
+        }
+    });
+
+    // requests sent after the held fetch are answered before it
+    time::sleep(Duration::from_millis(50)).await;
+    client.ping().await.unwrap();
+    assert_eq!(client.fetch("events", 0, 10, 1024).await.unwrap().len(), 1);
+    assert!(!waiting.is_finished());
+    client.publish("events", b"second").await.unwrap();
+    let records = time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].offset, 1);
+    assert_eq!(message(&records[0]), b"second");
+}
+
+#[tokio::test]
+async fn test_stream_pushes() {
+    let addr = start_server("stream").await;
+    let producer = MessageQueueClient::dial(&addr).await.unwrap();
+    let consumer = MessageQueueClient::dial(&addr).await.unwrap();
+    producer.publish("feed", b"m-0").await.unwrap();
+    consumer
+        .stream("feed", None, Duration::ZERO, 2)
+        .await
+        .unwrap();
+    for n in 1..3 {
+        producer
+            .publish("feed", format!("m-{n}").as_bytes())
+            .await
+            .unwrap();
+    }
+    for n in 0..2 {
+        let delivery = time::timeout(Duration::from_secs(5), consumer.next_delivery())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let topic = Topic::from_bytes(&delivery.message).unwrap();
+        assert_eq!(topic.message, format!("m-{n}").as_bytes());
+    }
+    // requests on a streaming connection are still answered
+    consumer.ping().await.unwrap();
+    // nothing more is pushed until the consumer hands out another credit
+    assert!(
+        time::timeout(Duration::from_millis(200), consumer.next_delivery())
+            .await
+            .is_err()
+    );
+    consumer.credit("feed", 1).await.unwrap();
+    let delivery = time::timeout(Duration::from_secs(5), consumer.next_delivery())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        Topic::from_bytes(&delivery.message).unwrap().message,
+        b"m-2"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_more_held_requests_than_the_cap() {
+    let addr = start_server("held").await;
+    let producer = MessageQueueClient::dial(&addr).await.unwrap();
+    let consumer = Arc::new(MessageQueueClient::dial(&addr).await.unwrap());
+    producer.publish("orders", b"first").await.unwrap();
+    let mut fetches = JoinSet::new();
+    for _ in 0..100 {
+        let consumer = consumer.clone();
+        fetches.spawn(async move {
+            consumer
+                .fetch_wait("orders", 1, 1, 1024, Duration::from_secs(10))
+                .await
+        });
+    }
+
+    // the requests over the cap are read once the held ones are answered
+    time::sleep(Duration::from_millis(100)).await;
+    producer.publish("orders", b"second").await.unwrap();
+    let answered = time::timeout(Duration::from_secs(5), async {
+        let mut answered = 0;
+        while let Some(fetched) = fetches.join_next().await {
+            let records = fetched.unwrap().unwrap();
+            assert_eq!(message(&records[0]), b"second");
+            answered += 1;
+        }
+        answered
+    })
+    .await
+    .unwrap();
+    assert_eq!(answered, 100);
+}
+
+#[tokio::test]
+async fn test_purge_and_truncate_queue() {
+    let addr = start_server("truncate").await;
+    let client = MessageQueueClient::dial(&addr).await.unwrap();
+    for n in 0..5 {
+        client
+            .publish("logs", format!("line-{n}").as_bytes())
+            .await
+            .unwrap();
+    }
+    assert_eq!(client.truncate_queue("logs", 2).await.unwrap(), 2);
+    let records = client.fetch("logs", 0, 10, 1024).await.unwrap();
+    assert_eq!(records[0].offset, 2);
+    assert_eq!(message(&records[0]), b"line-2");
+    // truncating before the start removes nothing
+    assert_eq!(client.truncate_queue("logs", 1).await.unwrap(), 0);
+    assert_eq!(client.purge_queue("logs").await.unwrap(), 3);
+    assert!(client.fetch("logs", 0, 10, 1024).await.unwrap().is_empty());
+
+    let missing = client.purge_queue("missing").await.unwrap_err();
+    assert_eq!(missing.kind(), ErrorKind::NotFound);
+    let missing = client.truncate_queue("missing", 1).await.unwrap_err();
+    assert_eq!(missing.kind(), ErrorKind::NotFound);
+
+    // a truncate without the offset is refused and leaves the queue alone
+    client.publish("logs", b"line-5").await.unwrap();
+    let mut conn = dial_raw(&addr).await;
+    let hello = Some(Hello::new(1, 0).to_bytes());
+    roundtrip(&mut conn, 13, None, hello).await;
+    let resp = roundtrip(&mut conn, 20, Some("logs"), None).await;
+    assert_eq!(resp.response_code, ResponseCode::Err as u16);
+    assert_eq!(
+        resp.response_message,
+        ResponseMessage::MessageBodyRequired as u16
+    );
+    assert_eq!(client.fetch("logs", 5, 10, 1024).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_stream_credits_and_redelivery() {
+    let addr = start_server("redelivery").await;
+    let producer = MessageQueueClient::dial(&addr).await.unwrap();
+    let consumer = MessageQueueClient::dial(&addr).await.unwrap();
+    producer.publish("tasks", b"t-0").await.unwrap();
+    consumer
+        .stream("tasks", Some("workers"), Duration::from_millis(200), 0)
+        .await
+        .unwrap();
+    // without credits nothing is pushed
+    assert!(
+        time::timeout(Duration::from_millis(200), consumer.next_delivery())
+            .await
+            .is_err()
+    );
+    consumer.credit("tasks", 2).await.unwrap();
+    let first = time::timeout(Duration::from_secs(5), consumer.next_delivery())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(Topic::from_bytes(&first.message).unwrap().message, b"t-0");
+
+    // a message not acked within the visibility timeout is pushed again
+    let again = time::timeout(Duration::from_secs(5), consumer.next_delivery())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(again.id, first.id);
+    consumer
+        .ack("tasks", Some("workers"), again.id)
+        .await
+        .unwrap();
+    consumer.credit("tasks", 1).await.unwrap();
+    assert!(
+        time::timeout(Duration::from_millis(500), consumer.next_delivery())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_long_poll_wakeup_and_timeout() {
+    let addr = start_server("long_poll").await;
+    let producer = MessageQueueClient::dial(&addr).await.unwrap();
+    let consumer = MessageQueueClient::dial(&addr).await.unwrap();
+    producer.publish("metrics", b"m-0").await.unwrap();
+
+    // nothing at the offset, the fetch is answered empty once the wait is over
+    let started = time::Instant::now();
+    let records = consumer
+        .fetch_wait("metrics", 1, 10, 1024, Duration::from_millis(300))
+        .await
+        .unwrap();
+    assert!(records.is_empty());
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    // a publish on another connection answers the waiting fetch right away
+    let started = time::Instant::now();
+    let (records, _) = tokio::join!(
+        consumer.fetch_wait("metrics", 1, 10, 1024, Duration::from_secs(10)),
+        async {
+            time::sleep(Duration::from_millis(100)).await;
+            producer.publish("metrics", b"m-1").await.unwrap();
+        }
+    );
+    let records = records.unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(message(&records[0]), b"m-1");
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_handshake_required() {
+    let addr = start_server("handshake").await;
+    let mut conn = dial_raw(&addr).await;
+    // commands before HELLO are refused
+    let resp = roundtrip(&mut conn, 3, None, None).await;
+    assert_eq!(resp.response_code, ResponseCode::Err as u16);
+    assert_eq!(
+        resp.response_message,
+        ResponseMessage::HandshakeRequired as u16
+    );
+
+    // a version older than the server speaks is refused with the supported range
+    let resp = roundtrip(&mut conn, 13, None, Some(Hello::new(0, 0).to_bytes())).await;
+    assert_eq!(resp.response_code, ResponseCode::Err as u16);
+    assert_eq!(
+        resp.response_message,
+        ResponseMessage::UnsupportedVersion as u16
+    );
+    assert_eq!(resp.response_data, Some(vec![0, 1, 0, 3]));
+    let resp = roundtrip(&mut conn, 3, None, None).await;
+    assert_eq!(
+        resp.response_message,
+        ResponseMessage::HandshakeRequired as u16
+    );
+
+    let resp = roundtrip(&mut conn, 13, None, Some(Hello::new(1, 0).to_bytes())).await;
+    assert_eq!(resp.response_code, ResponseCode::Ok as u16);
+    let resp = roundtrip(&mut conn, 3, None, None).await;
+    assert_eq!(resp.response_code, ResponseCode::Ok as u16);
+}
+
+#[tokio::test]
+async fn test_unknown_queue() {
+    let addr = start_server("unknown").await;
+    let client = MessageQueueClient::dial(&addr).await.unwrap();
+    // every request about a queue that doesn't exist gets the same reply
+    let not_found = |e: std::io::Error| assert_eq!(e.kind(), ErrorKind::NotFound);
+    not_found(client.ack("missing", None, 0).await.unwrap_err());
+    not_found(client.nack("missing", None, 0).await.unwrap_err());
+    not_found(
+        client
+            .offset_for_time("missing", SystemTime::now())
+            .await
+            .unwrap_err(),
+    );
+    not_found(
+        client
+            .alter_queue("missing", AlterQueueRequest::new())
+            .await
+            .unwrap_err(),
+    );
+    not_found(client.stats(Some("missing")).await.unwrap_err());
+    not_found(client.replay_dead_letters("missing").await.unwrap_err());
+    not_found(client.describe_queue("missing").await.unwrap_err());
+    not_found(client.delete_queue("missing").await.unwrap_err());
+}
@@ -1,17 +1,32 @@
-use mq::internal::log::CommitLog;
+use mq::internal::log::{
+    CommitLog, CompactionPolicy, Durability, LogConfig, RecordKey, RetentionPolicy, StorageError,
+    ENTRY_SIZE,
+};
+use mq::Queues;
+use std::fs::{self, OpenOptions};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 const PATH: &str = "test_data";
 
+// a fresh directory for a test, data left over from an earlier run is removed
+fn test_dir(name: &str) -> String {
+    let path = format!("{PATH}/{name}/");
+    let _ = fs::remove_dir_all(&path);
+    path
+}
+
 #[test]
 fn test_load_from_disk() {
-    let path_str = format!("{PATH}/segments/");
+    let path_str = test_dir("segments");
     let num_segments = 6;
     let queue_name = "test";
     let data = b"Hello World!";
     let segment_size = data.len() as u64;
-    let storage = CommitLog::new(queue_name, segment_size, &path_str);
-    create_segments(num_segments, storage, data);
-    let logs = CommitLog::restore_from_disk(segment_size, &path_str).unwrap();
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(segment_size), &path_str).unwrap();
+    create_segments(num_segments, &mut storage, data);
+    let logs = CommitLog::restore_from_disk(LogConfig::new(segment_size), &path_str).unwrap();
     for log in logs {
         if log.name == queue_name {
             assert_eq!(log.segments.len(), num_segments as usize);
@@ -20,11 +35,367 @@ fn test_load_from_disk() {
     }
 }
 
+#[test]
+fn test_recover_torn_write() {
+    let path_str = test_dir("recovery");
+    let queue_name = "torn";
+    let data = b"Hello World!";
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(1024), &path_str).unwrap();
+    create_segments(3, &mut storage, data);
+    let log_path = format!("{path_str}{queue_name}/000000000000.log");
+    drop(storage);
+    // simulate a crash where the last record only partially reached the log file
+    let log_file = OpenOptions::new().write(true).open(&log_path).unwrap();
+    log_file.set_len((data.len() * 3 - 5) as u64).unwrap();
+
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    assert_eq!(log.segments[0].current_offset, (data.len() * 2) as u64);
+    assert_eq!(log.read().unwrap(), data);
+    assert_eq!(log.read().unwrap(), data);
+    assert!(matches!(log.read(), Err(StorageError::LogIndexOutofBound)));
+
+    // appends after recovery continue from the truncated tail
+    log.save_to_disk(b"after crash").unwrap();
+    assert_eq!(log.read().unwrap(), b"after crash");
+}
+
+#[test]
+fn test_clean_shutdown_marker() {
+    let path_str = test_dir("shutdown");
+    let queue_name = "clean";
+    let marker = format!("{path_str}{queue_name}/clean_shutdown");
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(1024), &path_str).unwrap();
+    storage.save_to_disk(b"one").unwrap();
+    storage.save_to_disk(b"two").unwrap();
+    assert_eq!(storage.read().unwrap(), b"one");
+    storage.shutdown().unwrap();
+    assert!(Path::new(&marker).exists());
+    drop(storage);
+
+    // the marker only vouches for the files until they are written to again
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    assert!(!Path::new(&marker).exists());
+    assert_eq!(log.next_offset(), 2);
+    assert_eq!(log.read().unwrap(), b"two");
+    log.save_to_disk(b"three").unwrap();
+    drop(logs);
+
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    assert_eq!(log.next_offset(), 3);
+    assert_eq!(log.read().unwrap(), b"three");
+}
+
+#[test]
+fn test_retention_max_bytes() {
+    let path_str = test_dir("retention_bytes");
+    let queue_name = "bytes";
+    let data = b"Hello World!";
+    let mut storage =
+        CommitLog::new(queue_name, LogConfig::new(data.len() as u64), &path_str).unwrap();
+    create_segments(5, &mut storage, data);
+    assert_eq!(storage.read().unwrap(), data);
+
+    storage.set_retention(RetentionPolicy {
+        max_age: None,
+        max_bytes: Some(data.len() as u64 * 2),
+    });
+    assert_eq!(storage.apply_retention().unwrap(), 3);
+    assert_eq!(storage.segments.len(), 2);
+    let queue_path = format!("{path_str}{queue_name}");
+    assert!(!Path::new(&format!("{queue_path}/000000000000.log")).exists());
+    assert!(!Path::new(&format!("{queue_path}/000000000002.idx")).exists());
+    assert!(Path::new(&format!("{queue_path}/000000000003.log")).exists());
+
+    // the reader was inside a deleted segment so it resumes at the new log start
+    storage.save_to_disk(b"latest").unwrap();
+    assert_eq!(storage.read().unwrap(), data);
+    assert_eq!(storage.read().unwrap(), data);
+    assert_eq!(storage.read().unwrap(), b"latest");
+    assert!(Path::new(&format!("{queue_path}/000000000005.log")).exists());
+}
+
+#[test]
+fn test_retention_max_age() {
+    let path_str = test_dir("retention_age");
+    let queue_name = "age";
+    let data = b"Hello World!";
+    let mut storage =
+        CommitLog::new(queue_name, LogConfig::new(data.len() as u64), &path_str).unwrap();
+    create_segments(3, &mut storage, data);
+    storage.set_retention(RetentionPolicy {
+        max_age: Some(Duration::from_secs(3600)),
+        max_bytes: None,
+    });
+    assert_eq!(storage.apply_retention().unwrap(), 0);
+    storage.set_retention(RetentionPolicy {
+        max_age: Some(Duration::ZERO),
+        max_bytes: None,
+    });
+    // the active segment is kept even when it is past the retention age
+    assert_eq!(storage.apply_retention().unwrap(), 2);
+    assert_eq!(storage.segments.len(), 1);
+    assert_eq!(storage.read().unwrap(), data);
+
+    // the age is that of the records, touching the files doesn't keep them
+    create_segments(3, &mut storage, data);
+    std::thread::sleep(Duration::from_millis(300));
+    let queue_path = format!("{path_str}{queue_name}");
+    for entry in fs::read_dir(&queue_path).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            let file = OpenOptions::new().write(true).open(path).unwrap();
+            file.set_modified(SystemTime::now()).unwrap();
+        }
+    }
+    storage.set_retention(RetentionPolicy {
+        max_age: Some(Duration::from_millis(200)),
+        max_bytes: None,
+    });
+    assert_eq!(storage.apply_retention().unwrap(), 3);
+    assert_eq!(storage.segments.len(), 1);
+}
+
+#[test]
+fn test_consumer_groups() {
+    let path_str = test_dir("groups");
+    let queue_name = "orders";
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(1024), &path_str).unwrap();
+    for record in ["one", "two", "three"] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    // every group sees every message regardless of the others
+    assert_eq!(storage.read_group("billing").unwrap(), b"one");
+    assert_eq!(storage.read_group("billing").unwrap(), b"two");
+    assert_eq!(storage.read_group("shipping").unwrap(), b"one");
+    assert_eq!(storage.read().unwrap(), b"one");
+    assert!(matches!(
+        storage.read_group("../escape"),
+        Err(StorageError::InvalidName)
+    ));
+    assert!(Path::new(&format!("{path_str}{queue_name}/offsets/billing")).exists());
+
+    // group cursors are persisted under offsets/<group>
+    drop(storage);
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    let mut groups = log.groups();
+    groups.sort();
+    assert_eq!(groups, vec!["billing", "orders", "shipping"]);
+    assert_eq!(log.read_group("billing").unwrap(), b"three");
+    assert_eq!(log.read_group("shipping").unwrap(), b"two");
+    assert_eq!(log.read().unwrap(), b"two");
+}
+
+#[test]
+fn test_ack_and_redelivery() {
+    let path_str = test_dir("acks");
+    let queue_name = "jobs";
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(1024), &path_str).unwrap();
+    for record in ["one", "two", "three"] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    let timeout = Duration::from_secs(60);
+    let (one, data) = storage.deliver("workers", timeout).unwrap();
+    assert_eq!(data, b"one");
+    let (two, data) = storage.deliver("workers", timeout).unwrap();
+    assert_eq!(data, b"two");
+    storage.ack("workers", two).unwrap();
+    assert!(matches!(
+        storage.ack("workers", two),
+        Err(StorageError::NotInFlight)
+    ));
+
+    // a nacked message is handed out again before newer ones
+    storage.nack("workers", one).unwrap();
+    assert_eq!(
+        storage.deliver("workers", timeout).unwrap(),
+        (one, b"one".to_vec())
+    );
+    // so is a message whose visibility timeout expired
+    let (three, _) = storage.deliver("workers", Duration::ZERO).unwrap();
+    assert_eq!(
+        storage.deliver("workers", timeout).unwrap(),
+        (three, b"three".to_vec())
+    );
+
+    // messages still in flight are handed out again after a restart
+    drop(storage);
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    let (id, data) = log.deliver("workers", timeout).unwrap();
+    assert_eq!((id, data), (one, b"one".to_vec()));
+    log.ack("workers", id).unwrap();
+    assert_eq!(log.deliver("workers", timeout).unwrap().1, b"three");
+    assert!(matches!(
+        log.read_group("workers"),
+        Err(StorageError::LogIndexOutofBound)
+    ));
+}
+
+#[test]
+fn test_dead_letters() {
+    let path_str = test_dir("dead_letters");
+    let queue_name = "poison";
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(1024), &path_str).unwrap();
+    storage.set_max_deliveries(Some(2));
+    storage.save_to_disk(b"bad").unwrap();
+    storage.save_to_disk(b"good").unwrap();
+
+    let (bad, _) = storage.deliver("workers", Duration::ZERO).unwrap();
+    assert_eq!(storage.deliver("workers", Duration::ZERO).unwrap().0, bad);
+    // the delivery count survives a restart
+    drop(storage);
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    log.set_max_deliveries(Some(2));
+    assert!(log.take_dead_letters().is_empty());
+
+    let (id, data) = log.deliver("workers", Duration::from_secs(60)).unwrap();
+    assert_eq!(data, b"good");
+    log.ack("workers", id).unwrap();
+    let dead_letters = log.take_dead_letters();
+    assert_eq!(dead_letters.len(), 1);
+    assert_eq!(dead_letters[0].id, bad);
+    assert_eq!(dead_letters[0].group, "workers");
+    assert_eq!(dead_letters[0].deliveries, 2);
+    assert_eq!(dead_letters[0].message, b"bad");
+    // it stays in flight until it was moved, it's only taken again after the timeout
+    assert_eq!(log.in_flight("workers"), 1);
+    assert!(matches!(
+        log.deliver("workers", Duration::ZERO),
+        Err(StorageError::LogIndexOutofBound)
+    ));
+    assert!(log.take_dead_letters().is_empty());
+    log.ack("workers", bad).unwrap();
+    assert_eq!(log.in_flight("workers"), 0);
+}
+
+// test records are "key=value", records without a '=' have no key
+fn test_key(data: &[u8]) -> Option<RecordKey> {
+    let pos = data.iter().position(|b| *b == b'=')?;
+    Some(RecordKey {
+        key: data[..pos].to_vec(),
+        tombstone: pos == data.len() - 1,
+    })
+}
+
+#[test]
+fn test_compaction() {
+    let path_str = test_dir("compaction");
+    let queue_name = "changelog";
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(8), &path_str).unwrap();
+    for record in [
+        "a=1", "b=1", "a=2", "plain", "c=1", "b=", "a=3", "c=2", "d=1",
+    ] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    let segments = storage.segments.len();
+    // compaction is opt in
+    assert_eq!(storage.compact(test_key).unwrap(), 0);
+
+    storage.set_compaction(Some(CompactionPolicy {
+        tombstone_grace: Duration::from_secs(3600),
+    }));
+    // a=1, b=1, a=2, c=1 are superseded, the b tombstone is still in its grace period
+    assert_eq!(storage.compact(test_key).unwrap(), 4);
+    assert_eq!(storage.segments.len(), segments);
+    let mut records = Vec::new();
+    while let Ok(data) = storage.read() {
+        records.push(String::from_utf8(data).unwrap());
+    }
+    assert_eq!(records, vec!["plain", "b=", "a=3", "c=2", "d=1"]);
+    // compaction keeps the offsets the surviving messages were published at
+    let offsets: Vec<u64> = storage
+        .fetch(0, 10, u32::MAX)
+        .unwrap()
+        .iter()
+        .map(|(offset, _)| *offset)
+        .collect();
+    assert_eq!(offsets, vec![3, 5, 6, 7, 8]);
+
+    storage.set_compaction(Some(CompactionPolicy {
+        tombstone_grace: Duration::ZERO,
+    }));
+    assert_eq!(storage.compact(test_key).unwrap(), 1);
+
+    // the compacted segments survive a restart
+    drop(storage);
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(8), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    assert!(matches!(log.read(), Err(StorageError::LogIndexOutofBound)));
+    assert_eq!(log.save_to_disk(b"e=1").unwrap(), 9);
+    assert_eq!(log.read().unwrap(), b"e=1");
+}
+
 // #[test]
+#[test]
+fn test_interrupted_compaction() {
+    let path_str = test_dir("interrupted_compaction");
+    let queue_name = "changelog";
+    let queue_path = format!("{path_str}{queue_name}");
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(8), &path_str).unwrap();
+    for record in ["a=1", "b=1", "a=2", "c=1", "d=1"] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    let mut logs: Vec<String> = fs::read_dir(&queue_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path().to_string_lossy().to_string())
+        .filter(|path| path.ends_with(".log"))
+        .collect();
+    logs.sort();
+    let first_index = logs[0].replace(".log", ".idx");
+    let original_index = fs::read(&first_index).unwrap();
+    storage.set_compaction(Some(CompactionPolicy {
+        tombstone_grace: Duration::from_secs(3600),
+    }));
+    assert_eq!(storage.compact(test_key).unwrap(), 1);
+    drop(storage);
+
+    // the crash came after the log was renamed, the rewritten index is moved in place
+    fs::rename(&first_index, format!("{first_index}.compact")).unwrap();
+    fs::write(&first_index, original_index).unwrap();
+    // the crash came before the log was renamed, the rewrite is dropped
+    let second_log = &logs[1];
+    fs::write(format!("{second_log}.compact"), b"torn").unwrap();
+    fs::write(
+        format!("{}.compact", second_log.replace(".log", ".idx")),
+        b"torn",
+    )
+    .unwrap();
+
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(8), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    let records = log.fetch(0, 10, u32::MAX).unwrap();
+    assert_eq!(
+        records,
+        vec![
+            (1, b"b=1".to_vec()),
+            (2, b"a=2".to_vec()),
+            (3, b"c=1".to_vec()),
+            (4, b"d=1".to_vec())
+        ]
+    );
+    let leftovers = fs::read_dir(&queue_path)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|extension| extension == "compact")
+        })
+        .count();
+    assert_eq!(leftovers, 0);
+}
+
 fn _test_load_from_storage() {
     let path_str = "storage/queue/";
     let segment_size = 10 * 1024 * 1024;
-    let storage = CommitLog::restore_from_disk(segment_size, path_str).unwrap();
+    let storage = CommitLog::restore_from_disk(LogConfig::new(segment_size), path_str).unwrap();
     // println!("info ===> {:?}", storage);
     for store in storage {
         println!("{:?}", store.name);
@@ -38,7 +409,580 @@ fn _test_load_from_storage() {
     //     );
     // }
 }
-fn create_segments(no_segments: u32, mut store: CommitLog, data: &[u8]) {
+#[test]
+fn test_fetch_by_offset() {
+    let path_str = test_dir("fetch");
+    let queue_name = "events";
+    // two records per segment
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(12), &path_str).unwrap();
+    for n in 0..7u64 {
+        let record = format!("msg-{n:02}");
+        assert_eq!(storage.save_to_disk(record.as_bytes()).unwrap(), n);
+    }
+    assert_eq!(storage.segments.len(), 4);
+    assert_eq!((storage.start_offset(), storage.next_offset()), (0, 7));
+
+    let records = storage.fetch(3, 10, u32::MAX).unwrap();
+    let offsets: Vec<u64> = records.iter().map(|(offset, _)| *offset).collect();
+    assert_eq!(offsets, vec![3, 4, 5, 6]);
+    assert_eq!(records[0].1, b"msg-03");
+    assert_eq!(storage.fetch(1, 2, u32::MAX).unwrap().len(), 2);
+    // the first record is returned even when it is larger than max_bytes
+    assert_eq!(storage.fetch(0, 10, 1).unwrap().len(), 1);
+    assert_eq!(storage.fetch(0, 10, 12).unwrap().len(), 2);
+    assert!(storage.fetch(7, 10, u32::MAX).unwrap().is_empty());
+    // fetching doesn't move the consumer groups
+    assert_eq!(storage.read().unwrap(), b"msg-00");
+
+    // offsets keep increasing after a restart
+    drop(storage);
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(12), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    assert_eq!(log.next_offset(), 7);
+    assert_eq!(log.save_to_disk(b"msg-07").unwrap(), 7);
+    let records = log.fetch(6, 10, u32::MAX).unwrap();
+    assert_eq!(
+        records,
+        vec![(6, b"msg-06".to_vec()), (7, b"msg-07".to_vec())]
+    );
+}
+
+#[test]
+fn test_offset_for_time() {
+    let path_str = test_dir("offset_for_time");
+    let queue_name = "incidents";
+    let now = || {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    };
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(16), &path_str).unwrap();
+    let start = now();
+    for record in ["before-1", "before-2", "before-3"] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    std::thread::sleep(Duration::from_millis(20));
+    let incident = now();
+    for record in ["after-1", "after-2"] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    assert_eq!(storage.offset_for_time(start), 0);
+    assert_eq!(storage.offset_for_time(incident), 3);
+    assert_eq!(storage.offset_for_time(now() + 60_000), 5);
+
+    // offsets by time survive a restart
+    drop(storage);
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(16), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    let offset = log.offset_for_time(incident);
+    assert_eq!(log.fetch(offset, 10, u32::MAX).unwrap()[0].1, b"after-1");
+}
+
+#[test]
+fn test_durability_modes() {
+    let path_str = test_dir("durability");
+    let mut storage = CommitLog::new("payments", LogConfig::new(1024), &path_str).unwrap();
+    assert_eq!(storage.durability(), Durability::OsManaged);
+    storage.save_to_disk(b"one").unwrap();
+    assert!(!storage.is_synced());
+    storage.sync().unwrap();
+    assert!(storage.is_synced());
+
+    storage.set_durability(Durability::EveryWrite);
+    storage.save_to_disk(b"two").unwrap();
+    assert!(storage.is_synced());
+
+    storage.set_durability(Durability::EveryNMessages(2));
+    storage.save_to_disk(b"three").unwrap();
+    assert!(!storage.is_synced());
+    storage.save_to_disk(b"four").unwrap();
+    assert!(storage.is_synced());
+
+    storage.set_durability(Durability::EveryInterval(Duration::from_secs(3600)));
+    storage.save_to_disk(b"five").unwrap();
+    storage.sync_if_due().unwrap();
+    assert!(!storage.is_synced());
+    storage.set_durability(Durability::EveryInterval(Duration::ZERO));
+    storage.sync_if_due().unwrap();
+    assert!(storage.is_synced());
+    assert_eq!(storage.read().unwrap(), b"one");
+}
+
+#[test]
+fn test_batch_append_and_read() {
+    let path_str = test_dir("batches");
+    let queue_name = "metrics";
+    // room for four records per segment
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(12), &path_str).unwrap();
+    storage.save_to_disk(b"m-0").unwrap();
+    let batch: Vec<&[u8]> = vec![b"m-1", b"m-2", b"m-3"];
+    assert_eq!(storage.save_batch(&batch).unwrap(), vec![1, 2, 3]);
+    assert_eq!(storage.segments.len(), 1);
+    // a batch that doesn't fit the rest of the segment starts a new one
+    let batch: Vec<&[u8]> = vec![b"m-4", b"m-5", b"m-6"];
+    assert_eq!(storage.save_batch(&batch).unwrap(), vec![4, 5, 6]);
+    assert_eq!(storage.segments.len(), 2);
+    // nothing is written when the batch is larger than a segment
+    let batch: Vec<&[u8]> = vec![&[0u8; 8], &[0u8; 8]];
+    assert!(matches!(
+        storage.save_batch(&batch),
+        Err(StorageError::NoSpaceLeft)
+    ));
+    assert_eq!(storage.next_offset(), 7);
+
+    let records = storage.read_group_batch("dashboard", 5).unwrap();
+    let offsets: Vec<u64> = records.iter().map(|(offset, _)| *offset).collect();
+    assert_eq!(offsets, vec![0, 1, 2, 3, 4]);
+    assert_eq!(records[4].1, b"m-4");
+    let records = storage.read_group_batch("dashboard", 5).unwrap();
+    assert_eq!(records.len(), 2);
+    assert!(matches!(
+        storage.read_group_batch("dashboard", 5),
+        Err(StorageError::LogIndexOutofBound)
+    ));
+}
+
+#[test]
+fn test_index_size() {
+    let path_str = test_dir("index_size");
+    let queue_name = "small_index";
+    // room for two index entries per segment while the log has plenty
+    let config = LogConfig {
+        index_size: 2 * ENTRY_SIZE as u64,
+        ..LogConfig::new(1024)
+    };
+    let mut storage = CommitLog::new(queue_name, config, &path_str).unwrap();
+    for record in ["i-0", "i-1", "i-2"] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    assert_eq!(storage.segments.len(), 2);
+    // a batch needing more entries than a segment's index holds is refused
+    let batch: Vec<&[u8]> = vec![b"a", b"b", b"c"];
+    assert!(matches!(
+        storage.save_batch(&batch),
+        Err(StorageError::NoSpaceLeft)
+    ));
+    drop(storage);
+
+    let mut logs = CommitLog::restore_from_disk(config, &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    log.save_to_disk(b"i-3").unwrap();
+    log.save_to_disk(b"i-4").unwrap();
+    assert_eq!(log.segments.len(), 3);
+    let records = log.fetch(0, 10, 1024).unwrap();
+    assert_eq!(records.len(), 5);
+    assert_eq!(records[4], (4, b"i-4".to_vec()));
+}
+
+#[test]
+fn test_queue_settings() {
+    let path_str = test_dir("settings");
+    let queue_name = "configured";
+    let meta = format!("{path_str}{queue_name}/meta");
+    let config = LogConfig {
+        durability: Durability::EveryWrite,
+        max_message_size: Some(8),
+        ..LogConfig::new(64)
+    };
+    let mut storage = CommitLog::new(queue_name, config, &path_str).unwrap();
+    assert!(Path::new(&meta).exists());
+    let created_at = storage.settings().created_at;
+    storage.save_to_disk(b"small").unwrap();
+    assert!(matches!(
+        storage.save_to_disk(b"far too large"),
+        Err(StorageError::MessageTooLarge)
+    ));
+    assert!(matches!(
+        storage.set_config(LogConfig::new(0)),
+        Err(StorageError::InvalidConfig)
+    ));
+    storage.set_max_deliveries(Some(3));
+    drop(storage);
+
+    // the settings come back with the queue instead of the defaults passed in
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.iter_mut().find(|l| l.name == queue_name).unwrap();
+    let settings = log.settings();
+    assert_eq!(settings.created_at, created_at);
+    assert_eq!(
+        settings.config,
+        LogConfig {
+            max_deliveries: Some(3),
+            ..config
+        }
+    );
+    log.set_config(LogConfig {
+        max_message_size: None,
+        ..settings.config
+    })
+    .unwrap();
+    log.save_to_disk(b"far too large").unwrap();
+
+    // queues from before settings were persisted get the defaults written out
+    fs::remove_file(&meta).unwrap();
+    drop(logs);
+    let logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.iter().find(|l| l.name == queue_name).unwrap();
+    assert_eq!(log.config(), LogConfig::new(1024));
+    assert!(Path::new(&meta).exists());
+
+    // settings of a newer version are refused instead of replaced by the defaults
+    drop(logs);
+    let mut newer = fs::read(&meta).unwrap();
+    newer[0] += 1;
+    fs::write(&meta, &newer).unwrap();
+    assert!(matches!(
+        CommitLog::restore_from_disk(LogConfig::new(1024), &path_str),
+        Err(StorageError::InvalidSettings)
+    ));
+    assert_eq!(fs::read(&meta).unwrap(), newer);
+}
+
+#[test]
+fn test_queue_stats() {
+    let path_str = test_dir("stats");
+    let queue_name = "events";
+    // room for two records per segment
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(8), &path_str).unwrap();
+    for record in ["e-0", "e-1", "e-2", "e-3", "e-4"] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    assert_eq!(storage.segments.len(), 3);
+    assert_eq!(storage.size(), 15);
+    assert_eq!(storage.message_count(), 5);
+    assert_eq!(storage.lag(queue_name), Some(5));
+    assert_eq!(storage.lag("audit"), None);
+
+    // lag follows the group across segments
+    storage.read_group_batch("audit", 3).unwrap();
+    assert_eq!(storage.lag("audit"), Some(2));
+    storage.deliver("audit", Duration::from_secs(30)).unwrap();
+    assert_eq!(storage.lag("audit"), Some(1));
+    assert_eq!(storage.in_flight("audit"), 1);
+    assert_eq!(storage.lag(queue_name), Some(5));
+}
+
+#[tokio::test]
+async fn test_queues_lock_independently() {
+    let path_str = test_dir("queues");
+    let queues = Queues::new(LogConfig::new(1024), &path_str);
+    let first = queues.get_or_create("first").unwrap();
+    assert!(Arc::ptr_eq(&first, &queues.get_or_create("first").unwrap()));
+    assert!(queues.get("second").is_none());
+    // holding one queue doesn't keep the others from being created or appended to
+    let guard = first.lock().await;
+    let second = queues.get_or_create("second").unwrap();
+    assert_eq!(second.lock().await.save_to_disk(b"message").unwrap(), 0);
+    drop(guard);
+    assert_eq!(first.lock().await.save_to_disk(b"message").unwrap(), 0);
+    let mut names: Vec<_> = queues.all().into_iter().map(|(name, _)| name).collect();
+    names.sort();
+    assert_eq!(names, ["first", "second"]);
+}
+
+#[tokio::test]
+async fn test_create_and_delete_queues() {
+    let path_str = test_dir("admin");
+    let queues = Queues::new(LogConfig::new(1024), &path_str).with_auto_create(false);
+    assert!(!queues.auto_create());
+    let config = LogConfig {
+        max_message_size: Some(4),
+        ..LogConfig::new(64)
+    };
+    let orders = queues.create("orders", config).unwrap();
+    assert_eq!(orders.lock().await.config(), config);
+    queues.get_or_create("audit").unwrap();
+    assert!(matches!(
+        queues.create("orders", LogConfig::new(64)),
+        Err(StorageError::QueueExists)
+    ));
+    assert!(matches!(
+        queues.create("..", LogConfig::new(64)),
+        Err(StorageError::InvalidName)
+    ));
+    assert!(matches!(
+        queues.create("empty", LogConfig::new(0)),
+        Err(StorageError::InvalidConfig)
+    ));
+    assert_eq!(queues.names(), ["audit", "orders"]);
+
+    orders.lock().await.save_to_disk(b"o-0").unwrap();
+    assert!(queues.delete("orders").await.unwrap());
+    assert!(!queues.delete("orders").await.unwrap());
+    assert!(!Path::new(&format!("{path_str}orders")).exists());
+    assert_eq!(queues.names(), ["audit"]);
+    // handles taken before the delete can't append anymore
+    assert!(matches!(
+        orders.lock().await.save_to_disk(b"o-1"),
+        Err(StorageError::Deleted)
+    ));
+    // a queue created again under the same name starts empty
+    let orders = queues.create("orders", LogConfig::new(64)).unwrap();
+    assert_eq!(orders.lock().await.next_offset(), 0);
+
+    // queues whose files can't be created are not added
+    fs::write(format!("{path_str}blocked"), b"not a directory").unwrap();
+    assert!(matches!(
+        queues.create("blocked", LogConfig::new(64)),
+        Err(StorageError::IoError(_))
+    ));
+    assert!(queues.get_or_create("blocked").is_err());
+    assert_eq!(queues.names(), ["audit", "orders"]);
+}
+
+#[test]
+fn test_truncate_and_purge() {
+    let path_str = test_dir("truncate");
+    let queue_name = "events";
+    // room for two records per segment
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(8), &path_str).unwrap();
+    for record in ["e-0", "e-1", "e-2", "e-3", "e-4"] {
+        storage.save_to_disk(record.as_bytes()).unwrap();
+    }
+    assert_eq!(storage.read_group("ahead").unwrap(), b"e-0");
+    storage.read_group_batch("ahead", 3).unwrap();
+    assert_eq!(storage.read_group("behind").unwrap(), b"e-0");
+    let (removed, _) = storage.deliver("slow", Duration::from_secs(30)).unwrap();
+    for _ in 0..3 {
+        storage.deliver("slow", Duration::from_secs(30)).unwrap();
+    }
+
+    // e-0 goes with its segment, e-2 is cut from the front of the segment it shares
+    assert_eq!(storage.truncate_before(3).unwrap(), 3);
+    assert_eq!(storage.start_offset(), 3);
+    assert_eq!(storage.next_offset(), 5);
+    assert_eq!(storage.message_count(), 2);
+    assert_eq!(storage.fetch(0, 10, 1024).unwrap()[0], (3, b"e-3".to_vec()));
+    assert_eq!(storage.truncate_before(2).unwrap(), 0);
+    // groups behind the new start continue from it, the others keep their place
+    assert_eq!(storage.read_group("behind").unwrap(), b"e-3");
+    assert_eq!(storage.read_group("ahead").unwrap(), b"e-4");
+    assert_eq!(storage.lag(queue_name), Some(2));
+    // deliveries of removed messages can't be acknowledged anymore
+    assert_eq!(storage.in_flight("slow"), 1);
+    assert!(matches!(
+        storage.ack("slow", removed),
+        Err(StorageError::NotInFlight)
+    ));
+
+    // the truncated log is what gets restored
+    drop(storage);
+    let mut storage = CommitLog::restore_from_disk(LogConfig::new(8), &path_str)
+        .unwrap()
+        .into_iter()
+        .find(|log| log.name == queue_name)
+        .unwrap();
+    assert_eq!(storage.start_offset(), 3);
+    assert_eq!(storage.read().unwrap(), b"e-3");
+
+    // offsets keep counting after a purge, also across a restart
+    assert_eq!(storage.purge().unwrap(), 2);
+    assert_eq!(storage.message_count(), 0);
+    assert!(storage.read_group("behind").is_err());
+    drop(storage);
+    let mut storage = CommitLog::restore_from_disk(LogConfig::new(8), &path_str)
+        .unwrap()
+        .into_iter()
+        .find(|log| log.name == queue_name)
+        .unwrap();
+    assert_eq!(storage.next_offset(), 5);
+    assert_eq!(storage.save_to_disk(b"e-5").unwrap(), 5);
+    assert_eq!(storage.read_group("behind").unwrap(), b"e-5");
+    assert_eq!(storage.read().unwrap(), b"e-5");
+}
+
+#[test]
+fn test_migrate_v1_queue() {
+    let path_str = test_dir("format_v1");
+    let queue_dir = Path::new(&path_str).join("legacy");
+    fs::create_dir_all(queue_dir.join("offsets")).unwrap();
+    // a queue written with [offset][size] index entries, the first segment is closed
+    // and the index of the last one is preallocated
+    let v1_index = |records: &[&[u8]], len: usize| {
+        let mut index = Vec::new();
+        let mut offset = 0u32;
+        for record in records {
+            index.extend(offset.to_be_bytes());
+            index.extend((record.len() as u32).to_be_bytes());
+            offset += record.len() as u32;
+        }
+        index.resize(len.max(index.len()), 0);
+        index
+    };
+    fs::write(queue_dir.join("000000000000.log"), b"a-0a-1").unwrap();
+    fs::write(
+        queue_dir.join("000000000000.idx"),
+        v1_index(&[b"a-0", b"a-1"], 0),
+    )
+    .unwrap();
+    fs::write(queue_dir.join("000000000001.log"), b"a-2").unwrap();
+    fs::write(queue_dir.join("000000000001.idx"), v1_index(&[b"a-2"], 64)).unwrap();
+    // one record was read, the write offset was saved before the last append
+    let tracker: Vec<u8> = [0u32, 8, 0].iter().flat_map(|n| n.to_be_bytes()).collect();
+    fs::write(queue_dir.join("offsets").join("legacy"), tracker).unwrap();
+
+    // the migrated files are what a restart loads again
+    for next in [b"a-1", b"a-2"] {
+        let mut storage = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str)
+            .unwrap()
+            .into_iter()
+            .find(|log| log.name == "legacy")
+            .unwrap();
+        assert_eq!(
+            storage.fetch(0, 10, 1024).unwrap(),
+            vec![
+                (0, b"a-0".to_vec()),
+                (1, b"a-1".to_vec()),
+                (2, b"a-2".to_vec())
+            ]
+        );
+        assert_eq!(storage.next_offset(), 3);
+        assert_eq!(storage.read().unwrap(), next);
+    }
+    assert!(queue_dir.join("format").exists());
+}
+
+#[test]
+fn test_refuse_unknown_format() {
+    let path_str = test_dir("format_unknown");
+    let queue_name = "future";
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(1024), &path_str).unwrap();
+    storage.save_to_disk(b"kept").unwrap();
+    drop(storage);
+    let queue_dir = Path::new(&path_str).join(queue_name);
+    fs::write(queue_dir.join("format"), [9]).unwrap();
+    let log_len = fs::metadata(queue_dir.join("000000000000.log"))
+        .unwrap()
+        .len();
+    assert!(matches!(
+        CommitLog::restore_from_disk(LogConfig::new(1024), &path_str),
+        Err(StorageError::UnsupportedFormat(9))
+    ));
+    // an index that doesn't chain through its log isn't migrated either
+    fs::remove_file(queue_dir.join("format")).unwrap();
+    fs::remove_file(queue_dir.join("meta")).unwrap();
+    assert!(matches!(
+        CommitLog::restore_from_disk(LogConfig::new(1024), &path_str),
+        Err(StorageError::UnsupportedFormat(1))
+    ));
+    assert_eq!(
+        fs::metadata(queue_dir.join("000000000000.log"))
+            .unwrap()
+            .len(),
+        log_len
+    );
+}
+
+#[test]
+fn test_truncate_finished_on_restart() {
+    let path_str = test_dir("truncate_restart");
+    let queue_name = "interrupted";
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(8), &path_str).unwrap();
+    for n in 0..5 {
+        storage.save_to_disk(format!("t-{n}").as_bytes()).unwrap();
+    }
+    let queue_dir = Path::new(&path_str).join(queue_name);
+    let segment_files: Vec<(std::path::PathBuf, Vec<u8>)> = fs::read_dir(&queue_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "log" || ext == "idx")
+        })
+        .map(|path| (path.clone(), fs::read(path).unwrap()))
+        .collect();
+    assert_eq!(storage.truncate_before(3).unwrap(), 3);
+    drop(storage);
+
+    // the segments as they were before the truncate, like after a crash right after
+    // the new start was persisted
+    for (path, data) in segment_files {
+        fs::write(path, data).unwrap();
+    }
+    let mut storage = CommitLog::restore_from_disk(LogConfig::new(8), &path_str)
+        .unwrap()
+        .into_iter()
+        .find(|log| log.name == queue_name)
+        .unwrap();
+    assert_eq!(storage.start_offset(), 3);
+    assert_eq!(storage.message_count(), 2);
+    assert_eq!(storage.read().unwrap(), b"t-3");
+    assert_eq!(storage.fetch(0, 10, 1024).unwrap()[0], (3, b"t-3".to_vec()));
+}
+
+#[test]
+fn test_truncate_rewrite_interrupted() {
+    let path_str = test_dir("truncate_rewrite");
+    let queue_name = "interrupted";
+    let mut storage = CommitLog::new(queue_name, LogConfig::new(8), &path_str).unwrap();
+    for n in 0..5 {
+        storage.save_to_disk(format!("t-{n}").as_bytes()).unwrap();
+    }
+    let queue_dir = Path::new(&path_str).join(queue_name);
+    let mut logs: Vec<std::path::PathBuf> = fs::read_dir(&queue_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    logs.sort();
+    // the segment holding offset 3 is rewritten to start at it
+    let (log_path, index_path) = (logs[1].clone(), logs[1].with_extension("idx"));
+    let (old_log, old_index) = (fs::read(&log_path).unwrap(), fs::read(&index_path).unwrap());
+    assert_eq!(storage.truncate_before(3).unwrap(), 3);
+    drop(storage);
+    let (new_log, new_index) = (fs::read(&log_path).unwrap(), fs::read(&index_path).unwrap());
+    let compact_log_path = log_path.with_extension("log.compact");
+    let compact_index_path = index_path.with_extension("idx.compact");
+    let restore = || {
+        let mut storage = CommitLog::restore_from_disk(LogConfig::new(8), &path_str)
+            .unwrap()
+            .into_iter()
+            .find(|log| log.name == queue_name)
+            .unwrap();
+        assert_eq!(storage.start_offset(), 3);
+        assert_eq!(
+            storage.fetch(0, 10, 1024).unwrap(),
+            vec![(3, b"t-3".to_vec()), (4, b"t-4".to_vec())]
+        );
+        assert!(!compact_log_path.exists() && !compact_index_path.exists());
+    };
+
+    // the crash came after the log was renamed, the rewritten index is moved in place
+    fs::write(&index_path, &old_index).unwrap();
+    fs::write(&compact_index_path, &new_index).unwrap();
+    restore();
+
+    // the crash came before the log was renamed, the rewrite is dropped and the cut
+    // made again from the persisted log start
+    fs::write(&log_path, &old_log).unwrap();
+    fs::write(&index_path, &old_index).unwrap();
+    fs::write(&compact_log_path, &new_log).unwrap();
+    fs::write(&compact_index_path, &new_index).unwrap();
+    restore();
+}
+
+#[test]
+fn test_read_from_start() {
+    let path_str = test_dir("read_from_start");
+    let mut storage = CommitLog::new("replay", LogConfig::new(1024), &path_str).unwrap();
+    for n in 0..3 {
+        storage.save_to_disk(format!("msg-{n}").as_bytes()).unwrap();
+    }
+    assert_eq!(storage.read_from_start().unwrap(), b"msg-0");
+    assert_eq!(storage.read_from_start().unwrap(), b"msg-1");
+    drop(storage);
+
+    let mut logs = CommitLog::restore_from_disk(LogConfig::new(1024), &path_str).unwrap();
+    let log = logs.pop().unwrap();
+    let records: Vec<Vec<u8>> = log.collect();
+    assert_eq!(
+        records,
+        vec![b"msg-0".to_vec(), b"msg-1".to_vec(), b"msg-2".to_vec()]
+    );
+}
+
+fn create_segments(no_segments: u32, store: &mut CommitLog, data: &[u8]) {
     let mut next_seg = 0;
 
     loop {